@@ -1,15 +1,172 @@
-use std::path::Path;
+use std::fmt;
+use std::io::{self, BufRead, BufReader};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus, Stdio};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
 use minify_js::{Session, TopLevelMode, minify};
-use xshell::{Shell, cmd};
+use serde_json::{json, Value};
 use wasm_bindgen_cli_support::Bindgen;
 
-pub fn build_wasm(input_path: impl AsRef<Path>, is_release: bool, target_dir: impl AsRef<Path>) -> Option<()> {
-    let target_dir = target_dir.as_ref().to_str()?;
+/// A compiler message reported by cargo, see `cargo build --message-format=json`
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub level: String,
+    pub message: String,
+    /// Rendered message, as rustc would print it in the terminal (including ansi color codes)
+    pub rendered: String,
+    pub file: Option<String>,
+    pub line: Option<u64>,
+    pub column: Option<u64>,
+}
+
+impl Diagnostic {
+    /// Parse a "compiler-message" line from cargo json output
+    pub fn from_json(line: &str) -> Option<Diagnostic> {
+        let value: Value = serde_json::from_str(line).ok()?;
+        if value["reason"] != "compiler-message" { return None };
+        let message = &value["message"];
+        let span = message["spans"].as_array()?.iter()
+            .find(|span| span["is_primary"] == true);
+        Some(Diagnostic {
+            level: message["level"].as_str()?.to_string(),
+            message: message["message"].as_str()?.to_string(),
+            rendered: message["rendered"].as_str().unwrap_or_default().to_string(),
+            file: span.and_then(|span| span["file_name"].as_str()).map(|s| s.to_string()),
+            line: span.and_then(|span| span["line_start"].as_u64()),
+            column: span.and_then(|span| span["column_start"].as_u64()),
+        })
+    }
+    pub fn is_error(&self) -> bool {
+        self.level.starts_with("error")
+    }
+}
+
+#[derive(Debug)]
+pub enum BuildError {
+    /// Unable to start cargo
+    CargoSpawnError(io::Error),
+    /// Cargo exited with a non-zero exit code
+    CargoError {
+        status: ExitStatus,
+        stderr: String,
+        diagnostics: Vec<Diagnostic>,
+    },
+    BindgenError(String),
+    MinifyError(String),
+    /// Build was cancelled through its CancelToken
+    Cancelled,
+}
+
+impl BuildError {
+    /// First line of the error. The compiler errors that follow it for a cargo error
+    /// have already been printed by build_wasm, as cargo reported them.
+    pub fn summary(&self) -> String {
+        self.to_string().lines().next().unwrap_or_default().to_string()
+    }
+    /// Compiler errors reported by cargo. Other errors are converted into a single diagnostic.
+    pub fn diagnostics(&self) -> Vec<Diagnostic> {
+        match self {
+            Self::CargoError { diagnostics, .. } if !diagnostics.is_empty() => diagnostics.clone(),
+            _ => vec![Diagnostic {
+                level: "error".into(),
+                message: self.to_string(),
+                rendered: self.to_string(),
+                file: None,
+                line: None,
+                column: None,
+            }],
+        }
+    }
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::CargoSpawnError(err) => write!(f, "Unable to run cargo, {err}"),
+            Self::CargoError { status, stderr, diagnostics } => {
+                write!(f, "Failed to build wasm target, cargo {status}")?;
+                for diagnostic in diagnostics {
+                    match (&diagnostic.file, diagnostic.line, diagnostic.column) {
+                        (Some(file), Some(line), Some(column)) => 
+                            write!(f, "\n{file}:{line}:{column}: {}", diagnostic.message)?,
+                        _ => 
+                            write!(f, "\n{}: {}", diagnostic.level, diagnostic.message)?,
+                    }
+                }
+                if diagnostics.is_empty() {
+                    // Cargo itself failed (missing target, broken manifest etc), show its error lines
+                    for line in strip_ansi(stderr).lines().filter(|l| l.starts_with("error")) {
+                        write!(f, "\n{line}")?;
+                    }
+                }
+                Ok(())
+            },
+            Self::BindgenError(msg) => write!(f, "Failed to generate wasm bindings, {msg}"),
+            Self::MinifyError(msg) => write!(f, "Failed to minify javascript, {msg}"),
+            Self::Cancelled => write!(f, "Build was cancelled"),
+        }
+    }
+}
+
+impl std::error::Error for BuildError {}
+
+fn strip_ansi(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\x1b' { out.push(c); continue };
+        // Skip control sequence, like "\x1b[1m"
+        for c in chars.by_ref() {
+            if c.is_ascii_alphabetic() { break };
+        }
+    }
+    out
+}
+
+/// Serialize diagnostics as a json array, used to send them to the browser.
+pub fn diagnostics_to_json(diagnostics: &[Diagnostic]) -> String {
+    Value::Array(diagnostics.iter().map(|d| json!({
+        "level": d.level,
+        "message": d.message,
+        "rendered": d.rendered,
+        "file": d.file,
+        "line": d.line,
+        "column": d.column,
+    })).collect()).to_string()
+}
+
+/// Shared flag used to abort a running build
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// Build wasm target and generate js bindings.
+pub fn build_wasm(input_path: impl AsRef<Path>, is_release: bool, target_dir: impl AsRef<Path>) -> Result<(), BuildError> {
+    build_wasm_cancellable(input_path, is_release, target_dir, &CancelToken::new())
+}
+
+/// Same as build_wasm, but kills cargo and returns BuildError::Cancelled as soon as the token is cancelled.
+pub fn build_wasm_cancellable(input_path: impl AsRef<Path>, is_release: bool, target_dir: impl AsRef<Path>, cancel: &CancelToken) -> Result<(), BuildError> {
     let mut args = vec![
         "build",
         "--target", "wasm32-unknown-unknown",
-        "--target-dir", target_dir,
         "--color", "always",
+        "--message-format", "json-diagnostic-rendered-ansi",
     ];
     if is_release { 
         // Minimize bundle size by making the panic handler exit silently.
@@ -37,28 +194,149 @@ pub fn build_wasm(input_path: impl AsRef<Path>, is_release: bool, target_dir: im
         args.push("--release");
     };
     let args = args; // Remove mut
-    let sh = Shell::new().expect("Unable to create shell");
-    {
+    let mut child = Command::new("cargo")
+        .args(args)
+        .arg("--target-dir").arg(target_dir.as_ref())
         // This lets wasmdev::main know if cargo was started from within wasmdev::main
-        let _env_guard = sh.push_env("CARGO_WASMDEV", "1");
-        cmd!(sh, "cargo").args(args).quiet().run().ok()?;
+        .env("CARGO_WASMDEV", "1")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(BuildError::CargoSpawnError)?;
+
+    // Forward cargo progress output while keeping a copy of it, in case the build fails.
+    let stderr_thread = child.stderr.take().map(|stderr| thread::spawn(move || {
+        let mut captured = String::new();
+        for line in BufReader::new(stderr).lines() {
+            let Ok(line) = line else { break };
+            eprintln!("{line}");
+            captured.push_str(&line);
+            captured.push('\n');
+        }
+        captured
+    }));
+    let stdout_thread = child.stdout.take().map(|stdout| thread::spawn(move || {
+        let mut diagnostics = vec![];
+        for line in BufReader::new(stdout).lines() {
+            let Ok(line) = line else { break };
+            match Diagnostic::from_json(&line) {
+                Some(diagnostic) => {
+                    eprint!("{}", diagnostic.rendered);
+                    diagnostics.push(diagnostic);
+                },
+                // Build script output and artifact messages are not printed by cargo either.
+                None if line.starts_with('{') => (),
+                None => eprintln!("{line}"),
+            }
+        }
+        diagnostics
+    }));
+    let status = loop {
+        if cancel.is_cancelled() {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(BuildError::Cancelled);
+        }
+        match child.try_wait().map_err(BuildError::CargoSpawnError)? {
+            Some(status) => break status,
+            None => thread::sleep(Duration::from_millis(20)),
+        }
+    };
+    let mut diagnostics = stdout_thread.and_then(|t| t.join().ok()).unwrap_or_default();
+    let stderr = stderr_thread.and_then(|t| t.join().ok()).unwrap_or_default();
+    if !status.success() {
+        diagnostics.retain(|d| d.is_error());
+        return Err(BuildError::CargoError { status, stderr, diagnostics });
     }
-    let output_path = input_path.as_ref().parent()?;
+    if cancel.is_cancelled() { return Err(BuildError::Cancelled) };
+    let Some(output_path) = input_path.as_ref().parent() else {
+        return Err(BuildError::BindgenError(format!("'{}' has no parent directory", input_path.as_ref().display())));
+    };
     Bindgen::new()
         .input_path(&input_path)
         .web(true)
-        .map_err(|err| eprintln!("{}", err)).ok()?
+        .map_err(|err| BuildError::BindgenError(format!("{err:#}")))?
         .demangle(!is_release)
         .debug(!is_release)
         .remove_name_section(is_release)
         .remove_producers_section(is_release)
         .generate(output_path)
-        .map_err(|err| eprintln!("{}", err)).ok()
+        .map_err(|err| BuildError::BindgenError(format!("{err:#}")))
+}
+
+/// Files and directories, outside of the project's own "src" directory, that a build depends on.
+/// These are the Cargo.toml of the project and its workspace, build scripts, and the manifests and
+/// source directories of all local path dependencies. Resolved with "cargo metadata".
+pub fn build_dependency_paths(manifest_path: impl AsRef<Path>) -> io::Result<Vec<PathBuf>> {
+    let manifest_path = manifest_path.as_ref();
+    let output = Command::new("cargo")
+        .args(["metadata", "--format-version", "1", "--manifest-path"])
+        .arg(manifest_path)
+        .stderr(Stdio::piped())
+        .output()?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(io::Error::other(format!("cargo metadata failed: {}", stderr.trim())));
+    }
+    let invalid_data = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, format!("cargo metadata: {msg}"));
+    let metadata: Value = serde_json::from_slice(&output.stdout).map_err(|err| invalid_data(&err.to_string()))?;
+    let packages = metadata["packages"].as_array().ok_or_else(|| invalid_data("missing packages"))?;
+    let packages: HashMap<&str, &Value> = packages.iter()
+        .filter_map(|package| Some((package["id"].as_str()?, package)))
+        .collect();
+    let dependencies: HashMap<&str, Vec<&str>> = metadata["resolve"]["nodes"].as_array()
+        .ok_or_else(|| invalid_data("missing resolve graph"))?
+        .iter()
+        .filter_map(|node| Some((
+            node["id"].as_str()?, 
+            node["dependencies"].as_array()?.iter().filter_map(|id| id.as_str()).collect()
+        )))
+        .collect();
+    let Some(root_id) = packages.iter()
+        .find(|(_, package)| package["manifest_path"].as_str().map(Path::new) == Some(manifest_path))
+        .map(|(id, _)| *id) else {
+        return Err(invalid_data(&format!("no package with manifest '{}'", manifest_path.display())));
+    };
+
+    let mut paths = vec![];
+    if let Some(workspace_root) = metadata["workspace_root"].as_str() {
+        let workspace_manifest_path = Path::new(workspace_root).join("Cargo.toml");
+        if workspace_manifest_path != manifest_path {
+            paths.push(workspace_manifest_path);
+        }
+    }
+    // Walk the dependency graph from the project, only local packages (without "source") are of interest.
+    let mut visited = HashSet::new();
+    let mut queue = vec![root_id];
+    while let Some(id) = queue.pop() {
+        if !visited.insert(id) { continue };
+        let Some(package) = packages.get(id) else { continue };
+        if !package["source"].is_null() { continue };
+        let is_root = id == root_id;
+        if let Some(manifest_path) = package["manifest_path"].as_str() {
+            paths.push(PathBuf::from(manifest_path));
+        }
+        for target in package["targets"].as_array().into_iter().flatten() {
+            let Some(src_path) = target["src_path"].as_str().map(Path::new) else { continue };
+            let kinds: Vec<&str> = target["kind"].as_array().into_iter().flatten().filter_map(|k| k.as_str()).collect();
+            if kinds.contains(&"custom-build") {
+                paths.push(src_path.to_path_buf());
+            } else if !is_root && kinds.iter().any(|k| matches!(*k, "lib" | "rlib" | "cdylib" | "staticlib" | "proc-macro")) {
+                // The project's own sources are already watched.
+                paths.extend(src_path.parent().map(Path::to_path_buf));
+            }
+        }
+        queue.extend(dependencies.get(id).into_iter().flatten());
+    }
+    paths.sort();
+    paths.dedup();
+    Ok(paths)
 }
 
-pub fn minify_javascript(code_in: &[u8]) -> Option<Vec<u8>>{
+pub fn minify_javascript(code_in: &[u8]) -> Result<Vec<u8>, BuildError> {
     let session = Session::new();
     let mut code_out = vec![];
-    minify(&session, TopLevelMode::Module, code_in, &mut code_out).ok()?;
-    Some(code_out)
-} 
\ No newline at end of file
+    minify(&session, TopLevelMode::Module, code_in, &mut code_out)
+        .map_err(|err| BuildError::MinifyError(format!("{err:?}")))?;
+    Ok(code_out)
+}
@@ -0,0 +1,100 @@
+use std::fs;
+use std::io::{Result, Write};
+use std::path::{Path, PathBuf};
+use flate2::{Compression, write::GzEncoder};
+use brotli::enc::BrotliEncoderParams;
+
+/// Content codings supported for http responses, see RFC 9110 section 8.4.1
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Encoding {
+    Gzip,
+    Brotli,
+}
+
+impl Encoding {
+    /// All encodings, in order of preference when a client accepts more than one.
+    pub const ALL: [Encoding; 2] = [Encoding::Brotli, Encoding::Gzip];
+
+    /// Name used in "Accept-Encoding" and "Content-Encoding" headers.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Gzip => "gzip",
+            Self::Brotli => "br",
+        }
+    }
+
+    /// Extension of a precompressed sibling file, like "index.wasm.br"
+    pub fn file_extension(&self) -> &'static str {
+        match self {
+            Self::Gzip => "gz",
+            Self::Brotli => "br",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Encoding> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "gzip" | "x-gzip" => Some(Self::Gzip),
+            "br" => Some(Self::Brotli),
+            _ => None,
+        }
+    }
+
+    /// Compress with a quality level that is fast enough to be used on each change of a file.
+    pub fn compress(&self, bytes: &[u8]) -> Vec<u8> {
+        self.compress_with_level(bytes, false)
+    }
+
+    /// Compress with the highest quality level, slow but produces the smallest output.
+    pub fn compress_best(&self, bytes: &[u8]) -> Vec<u8> {
+        self.compress_with_level(bytes, true)
+    }
+
+    fn compress_with_level(&self, bytes: &[u8], best: bool) -> Vec<u8> {
+        let mut out = vec![];
+        match self {
+            Self::Gzip => {
+                let level = if best { Compression::best() } else { Compression::fast() };
+                let mut encoder = GzEncoder::new(&mut out, level);
+                // Writing to a Vec can't fail
+                let _ = encoder.write_all(bytes);
+                let _ = encoder.finish();
+            },
+            Self::Brotli => {
+                let params = BrotliEncoderParams { quality: if best { 11 } else { 4 }, ..Default::default() };
+                let _ = brotli::BrotliCompress(&mut &bytes[..], &mut out, &params);
+            },
+        }
+        out
+    }
+}
+
+/// Files with these extensions are text-like or wasm, so compression makes them smaller.
+const COMPRESSIBLE_EXTENSIONS: &[&str] = &[
+    "html", "js", "mjs", "css", "json", "webmanifest", "manifest", "map", "wasm", "svg", "txt", "xml", "csv",
+];
+
+pub fn is_compressible_path(path: impl AsRef<Path>) -> bool {
+    let Some(extension) = path.as_ref().extension().and_then(|e| e.to_str()) else { return false };
+    COMPRESSIBLE_EXTENSIONS.contains(&extension.to_ascii_lowercase().as_str())
+}
+
+/// Path of a precompressed sibling, like: "index.wasm" -> "index.wasm.br"
+pub fn sibling_path(path: impl AsRef<Path>, encoding: Encoding) -> PathBuf {
+    let mut path = path.as_ref().as_os_str().to_owned();
+    path.push(".");
+    path.push(encoding.file_extension());
+    PathBuf::from(path)
+}
+
+/// Write a ".gz" and ".br" sibling next to the file, if it is compressible and at least min_len bytes.
+/// Returns true if siblings were written.
+pub fn write_precompressed_siblings(path: impl AsRef<Path>, min_len: usize) -> Result<bool> {
+    let path = path.as_ref();
+    if !is_compressible_path(path) { return Ok(false) };
+    let contents = fs::read(path)?;
+    if contents.len() < min_len { return Ok(false) };
+    for encoding in Encoding::ALL {
+        fs::write(sibling_path(path, encoding), encoding.compress_best(&contents))?;
+    }
+    Ok(true)
+}
@@ -2,6 +2,105 @@
 use std::io::{Result, Error, ErrorKind};
 use std::fs;
 use std::path::{Path, PathBuf};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use ignore::gitignore::Gitignore;
+
+/// Editor swap/backup files, os metadata and vcs directories. These are never served or watched.
+pub const DEFAULT_EXCLUDES: &[&str] = &[
+    "**/.git/**",
+    "**/.gitignore",
+    "**/.DS_Store",
+    "**/Thumbs.db",
+    "*~",
+    "*.swp",
+    "*.swo",
+    "*.swx",
+    "*.tmp",
+    "**/.#*",
+    "**/#*#",
+];
+
+/// Decides which files below a root directory are included, based on include/exclude globs and
+/// .gitignore files. Globs are matched against the path relative to the root directory.
+#[derive(Debug, Clone)]
+pub struct FileFilter {
+    root: PathBuf,
+    include: Option<GlobSet>,
+    exclude: GlobSet,
+    /// Sorted with the most nested .gitignore last, since it has priority.
+    /// Paths must be in the same form as root (absolute or relative) for these to apply.
+    gitignores: Vec<Gitignore>,
+}
+
+impl FileFilter {
+    /// An empty include list includes all files. DEFAULT_EXCLUDES are always excluded.
+    pub fn new(root: impl AsRef<Path>, include: &[&str], exclude: &[&str], use_gitignore: bool) -> Result<FileFilter> {
+        let root = root.as_ref().to_path_buf();
+        let build_glob_set = |globs: &mut dyn Iterator<Item = &&str>| -> Result<GlobSet> {
+            let mut builder = GlobSetBuilder::new();
+            for glob in globs {
+                builder.add(Glob::new(glob).map_err(|err| Error::new(ErrorKind::InvalidInput, err))?);
+            }
+            builder.build().map_err(|err| Error::new(ErrorKind::InvalidInput, err))
+        };
+        let include = if include.is_empty() { None } else { Some(build_glob_set(&mut include.iter())?) };
+        let exclude = build_glob_set(&mut DEFAULT_EXCLUDES.iter().chain(exclude.iter()))?;
+        let gitignores = if use_gitignore { find_gitignores(&root)? } else { vec![] };
+        Ok(FileFilter { root, include, exclude, gitignores })
+    }
+
+    /// Check if a path should be included. Include globs only apply to existing files, since
+    /// directories and removed paths might contain included files.
+    pub fn is_included(&self, path: impl AsRef<Path>) -> bool {
+        let path = path.as_ref();
+        let is_dir = path.is_dir();
+        let rel_path = path.strip_prefix(&self.root).unwrap_or(path);
+        if self.exclude.is_match(rel_path) { return false };
+        if let (Some(include), true) = (&self.include, path.is_file()) {
+            if !include.is_match(rel_path) { return false };
+        }
+        for gitignore in self.gitignores.iter().rev() {
+            if !path.starts_with(gitignore.path()) { continue };
+            let matched = gitignore.matched_path_or_any_parents(path, is_dir);
+            if matched.is_ignore() { return false };
+            if matched.is_whitelist() { return true };
+        }
+        true
+    }
+
+    /// Recursively list all included files in directory
+    pub fn list_files(&self, path: impl AsRef<Path>) -> Result<Vec<PathBuf>> {
+        let mut files = list_files_recursively(path)?;
+        files.retain(|file| self.is_included(file));
+        Ok(files)
+    }
+}
+
+/// Find .gitignore files that apply to root: in its parent directories up to the repository root,
+/// in root itself and in its sub directories. Less nested files are sorted first.
+fn find_gitignores(root: &Path) -> Result<Vec<Gitignore>> {
+    let mut paths = vec![];
+    if !root.join(".git").exists() {
+        let mut parent_paths = vec![];
+        for dir in root.ancestors().skip(1) {
+            parent_paths.insert(0, dir.join(".gitignore"));
+            if dir.join(".git").exists() {
+                // Only use .gitignore files from parent dirs if we are inside a repository
+                paths = parent_paths;
+                break;
+            }
+        }
+    }
+    let mut nested_paths: Vec<PathBuf> = list_files_recursively(root)?.into_iter()
+        .filter(|p| p.file_name().is_some_and(|name| name == ".gitignore"))
+        .collect();
+    nested_paths.sort_by_key(|p| p.components().count());
+    paths.extend(nested_paths);
+    Ok(paths.iter()
+        .filter(|p| p.is_file())
+        .map(|p| Gitignore::new(p).0)
+        .collect())
+}
 
 /// Recusivly list files in directory
 pub fn list_files_recursively(path: impl AsRef<Path>) -> Result<Vec<PathBuf>> {
@@ -24,7 +123,7 @@ pub fn list_files_recursively(path: impl AsRef<Path>) -> Result<Vec<PathBuf>> {
 
     let mut paths = vec![path.as_ref().to_path_buf()];
     for _ in 0..256 { // Avoid infinite loop for linked files/dirs
-        if paths.len() == 0 { break }
+        if paths.is_empty() { break }
         paths = traverse(&mut paths)?;
     }
     Ok(files)
@@ -54,4 +153,84 @@ pub fn create_parent_dir_all(path: impl AsRef<Path>) -> Result<()> {
     let path = path.as_ref();
     let path = path.parent().ok_or(Error::new(ErrorKind::NotFound, "Unable to get parent directory"))?;
     fs::create_dir_all(path)
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// A directory of files below the system temp directory, removed when dropped.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn with_files(files: &[(&str, &str)]) -> TempDir {
+            static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+            let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+            let dir = TempDir(std::env::temp_dir().join(format!("wasmdev_fs_test_{}_{}", std::process::id(), id)));
+            for (path, contents) in files {
+                let path = dir.0.join(path);
+                create_parent_dir_all(&path).unwrap();
+                fs::write(path, contents).unwrap();
+            }
+            dir
+        }
+
+        /// Paths of the included files, relative to the directory and sorted.
+        fn included(&self, filter: &FileFilter) -> Vec<String> {
+            let mut files: Vec<String> = filter.list_files(&self.0).unwrap().iter()
+                .map(|path| path.strip_prefix(&self.0).unwrap().to_str().unwrap().replace('\\', "/"))
+                .collect();
+            files.sort();
+            files
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn default_excludes() {
+        let dir = TempDir::with_files(&[
+            ("index.css", ""), ("img/logo.svg", ""), (".DS_Store", ""), ("img/Thumbs.db", ""),
+            ("main.rs~", ""), (".index.css.swp", ""), ("img/.#logo.svg", ""), (".git/HEAD", ""),
+        ]);
+        let filter = FileFilter::new(&dir.0, &[], &[], false).unwrap();
+        assert_eq!(dir.included(&filter), ["img/logo.svg", "index.css"]);
+    }
+
+    #[test]
+    fn include_and_exclude_globs() {
+        let dir = TempDir::with_files(&[
+            ("index.css", ""), ("main.rs", ""), ("img/logo.svg", ""), ("img/raw/logo.psd", ""), ("fonts/a.woff2", ""),
+        ]);
+        let filter = FileFilter::new(&dir.0, &["**/*.css", "img/**"], &["**/*.psd"], false).unwrap();
+        assert_eq!(dir.included(&filter), ["img/logo.svg", "index.css"]);
+        let filter = FileFilter::new(&dir.0, &[], &["**/*.rs", "fonts/**"], false).unwrap();
+        assert_eq!(dir.included(&filter), ["img/logo.svg", "img/raw/logo.psd", "index.css"]);
+    }
+
+    #[test]
+    fn include_globs_do_not_apply_to_directories() {
+        let dir = TempDir::with_files(&[("img/logo.svg", "")]);
+        let filter = FileFilter::new(&dir.0, &["**/*.svg"], &[], false).unwrap();
+        assert!(filter.is_included(dir.0.join("img")));
+        assert!(filter.is_included(dir.0.join("removed.svg")));
+    }
+
+    #[test]
+    fn nested_gitignores() {
+        let dir = TempDir::with_files(&[
+            (".gitignore", "*.log\ndrafts/\n"),
+            ("app.log", ""), ("index.css", ""), ("drafts/post.md", ""),
+            ("logs/.gitignore", "!keep.log\nsecret/\n"),
+            ("logs/keep.log", ""), ("logs/other.log", ""), ("logs/secret/key.txt", ""), ("logs/public.txt", ""),
+        ]);
+        let filter = FileFilter::new(&dir.0, &[], &[], true).unwrap();
+        assert_eq!(dir.included(&filter), ["index.css", "logs/keep.log", "logs/public.txt"]);
+        let filter = FileFilter::new(&dir.0, &[], &[], false).unwrap();
+        assert_eq!(dir.included(&filter).len(), 7);
+    }
+}
@@ -1,5 +1,6 @@
-
 #[cfg(not(target_family = "wasm"))]
 pub mod fs;
 #[cfg(not(target_family = "wasm"))]
 pub mod code;
+#[cfg(not(target_family = "wasm"))]
+pub mod compress;
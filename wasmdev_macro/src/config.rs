@@ -1,6 +1,6 @@
 use std::{env, str::from_utf8, fs, collections::HashSet};
 
-use proc_macro2::{TokenStream, TokenTree, Span};
+use proc_macro2::{TokenStream, TokenTree, Span, Delimiter};
 use quote::quote;
 
 use crate::core;
@@ -22,6 +22,20 @@ pub(crate) struct AttrConfig {
     pub(crate) path: Attr<String>,
     pub(crate) addr: Attr<String>,
     pub(crate) watch: Attr<bool>,
+    pub(crate) debounce: Attr<u64>,
+    pub(crate) poll: Attr<bool>,
+    pub(crate) poll_interval: Attr<u64>,
+    pub(crate) compress: Attr<bool>,
+    pub(crate) precompress: Attr<bool>,
+    pub(crate) precompress_min_size: Attr<u64>,
+    pub(crate) spa_fallback: Attr<bool>,
+    pub(crate) proxy: Attr<Vec<(String, String)>>,
+    pub(crate) cors_origins: Attr<Vec<String>>,
+    pub(crate) cors_methods: Attr<Vec<String>>,
+    pub(crate) cors_headers: Attr<Vec<String>>,
+    pub(crate) include: Attr<Vec<String>>,
+    pub(crate) exclude: Attr<Vec<String>>,
+    pub(crate) gitignore: Attr<bool>,
 }
 
 pub(crate) struct BuildConfig {
@@ -31,12 +45,25 @@ pub(crate) struct BuildConfig {
     pub(crate) index_js: String,
     pub(crate) index_html: String,
     pub(crate) target_path: String,
+    pub(crate) dist_path: String,
     pub(crate) wasm_path: String,
     pub(crate) index_js_path: String,
     pub(crate) index_wasm_path: String,
     pub(crate) proj_html_path: String,
+    pub(crate) proj_manifest_path: String,
     pub(crate) proj_static_path: String,
     pub(crate) proj_src_path: String,
+    pub(crate) static_include: Vec<String>,
+    pub(crate) static_exclude: Vec<String>,
+}
+
+impl BuildConfig {
+    /// Filter for static assets, same for dev server and release build.
+    pub(crate) fn static_file_filter(&self) -> std::io::Result<core::fs::FileFilter> {
+        let include: Vec<&str> = self.static_include.iter().map(|s| s.as_str()).collect();
+        let exclude: Vec<&str> = self.static_exclude.iter().map(|s| s.as_str()).collect();
+        core::fs::FileFilter::new(&self.proj_static_path, &include, &exclude, self.attrs.gitignore.value)
+    }
 }
 
 impl TryInto<BuildConfig> for AttrConfig {
@@ -55,7 +82,8 @@ impl TryInto<BuildConfig> for AttrConfig {
         let release_mode     = if is_release {"release"} else {"debug"};
         let index_js         = if is_release {index_js.split("// -- debug -- \\").next().unwrap()} else {index_js};
         let index_html       = format!("{index_html}\n<script type=\"module\">{index_js}</script>"); 
-        let target_path      = format!("target/wasmdev-build-cache");
+        let target_path      = "target/wasmdev-build-cache".to_string();
+        let dist_path        = format!("target/dist/{proj_name}");
         let out_path         = format!("{target_path}/wasm32-unknown-unknown");
         let wasm_path        = format!("{out_path}/{release_mode}/{proj_name}.wasm");
         let index_js_path    = format!("{out_path}/{release_mode}/{proj_name}.js");
@@ -63,6 +91,10 @@ impl TryInto<BuildConfig> for AttrConfig {
         let proj_html_path   = format!("{proj_dir}/{}/index.html", &self.path.value);
         let proj_static_path = format!("{proj_dir}/{}", &self.path.value);
         let proj_src_path    = format!("{proj_dir}/src");
+        let proj_manifest_path = format!("{proj_dir}/Cargo.toml");
+        let static_include   = self.include.value.clone();
+        // Rust source files are never static assets.
+        let static_exclude   = ["*.rs".to_string()].into_iter().chain(self.exclude.value.clone()).collect();
     
         Ok(BuildConfig {
             attrs: self,
@@ -72,10 +104,14 @@ impl TryInto<BuildConfig> for AttrConfig {
             index_wasm_path,
             is_release,
             proj_html_path,
+            proj_manifest_path,
             proj_name,
             proj_static_path,
             proj_src_path,
+            static_include,
+            static_exclude,
             target_path,
+            dist_path,
             wasm_path,
         })
     }
@@ -86,6 +122,11 @@ impl TryInto<BuildConfig> for AttrConfig {
 pub(crate) fn build_all_web_assets(config: &BuildConfig) -> Result<TokenStream, TokenStream> {
     use wasmdev_core::{fs::list_files_recursively, code};
 
+    let static_file_filter = match config.static_file_filter() {
+        Ok(filter) => filter,
+        Err(err) => return compiler_error!("Invalid include or exclude pattern, {err}"),
+    };
+
     enum Error{
         CompilerError(TokenStream),
         IOError(std::io::Error),
@@ -103,15 +144,17 @@ pub(crate) fn build_all_web_assets(config: &BuildConfig) -> Result<TokenStream,
         }
     }
     
-    let Some(_)       = code::build_wasm(&config.wasm_path, config.is_release, &config.target_path)
-                            else { return compiler_error!("Failed to build wasm target") };
+    if let Err(err)   = code::build_wasm(&config.wasm_path, config.is_release, &config.target_path)
+                            { return compiler_error!("{err}") };
     let Ok(wasm_code) = fs::read(&config.index_wasm_path)
                             else { return compiler_error!("Failed to read wasm code from {}", config.index_wasm_path) };
     let Ok(js_code)   = fs::read(&config.index_js_path)
                             else { return compiler_error!("Failed to read js code from {}", config.index_js_path) };
-    let Some(js_code) = code::minify_javascript(&js_code)
-                            else { return compiler_error!("Failed to minify js code") };
-    let dist_path     = &format!("target/dist/{}", config.proj_name);
+    let js_code       = match code::minify_javascript(&js_code) {
+                            Ok(js_code) => js_code,
+                            Err(err) => return compiler_error!("{err}"),
+                        };
+    let dist_path     = &config.dist_path;
     let html_code = (|| -> Option<String>{
         let html_code = fs::read(&config.proj_html_path).ok()?;
         let html_code = from_utf8(&html_code).ok()?;
@@ -124,15 +167,14 @@ pub(crate) fn build_all_web_assets(config: &BuildConfig) -> Result<TokenStream,
         fs::write(format!("{dist_path}/index.js"), js_code)?;
         fs::write(format!("{dist_path}/index.html"), html_code)?;
 
-        let file_paths = list_files_recursively(&config.proj_static_path)?;
+        let file_paths = static_file_filter.list_files(&config.proj_static_path)?;
         let file_path_iter = file_paths.iter()
             .filter_map(|p| p.to_str())
-            .filter(|p| !p.ends_with(".rs"))          // Don't export src files.
             .filter(|p| !p.ends_with("/index.html")); // index.html already handled.
 
         // Clean up old files that were removed since last build:
         {
-            let old_files = list_files_recursively(&dist_path)?;
+            let old_files = list_files_recursively(dist_path)?;
             let mut old_file_paths: HashSet<_> = old_files.iter()
                 .filter_map(|p| p.to_str())
                 .map(|p| p.to_string().replace(dist_path, ""))
@@ -148,15 +190,15 @@ pub(crate) fn build_all_web_assets(config: &BuildConfig) -> Result<TokenStream,
             for file_path in files_to_remove {
                 fs::remove_file(file_path)?;
             }
-            core::fs::remove_empty_dirs(&dist_path)?;
+            core::fs::remove_empty_dirs(dist_path)?;
         }
 
         for file_path in file_path_iter {
             let file_contents = fs::read(file_path)?;
             let file_contents = if file_path.ends_with(".js") { 
                 match code::minify_javascript(&file_contents) {
-                    Some(code) => Ok(code),
-                    None => compiler_error!("Unable to minify js file: '{file_path}'"),
+                    Ok(code) => Ok(code),
+                    Err(err) => compiler_error!("Unable to minify js file: '{file_path}', {err}"),
                 }?
             } else { file_contents };
             let file_rel_path = file_path.replace(&config.proj_static_path, "");
@@ -164,10 +206,16 @@ pub(crate) fn build_all_web_assets(config: &BuildConfig) -> Result<TokenStream,
             core::fs::create_parent_dir_all(&file_dist_path)?;
             fs::write(file_dist_path, file_contents)?;
         }
+        // Write .gz and .br siblings for static hosts that can serve precompressed files:
+        if config.attrs.precompress.value {
+            let min_len = config.attrs.precompress_min_size.value as usize;
+            for file_path in list_files_recursively(dist_path)? {
+                core::compress::write_precompressed_siblings(file_path, min_len)?;
+            }
+        }
         // Abuse "include_bytes" to make sure static web assets invalidate cargo build cache
         let tt_invalidate_static_asset_cache = TokenStream::from_iter(file_paths.iter()
             .filter_map(|p| p.to_str())
-            .filter(|p| !p.ends_with(".rs"))
             .map(|p| quote!{ include_bytes!(#p); })
         );
         eprintln!("\x1b[0m\x1b[0m\x1b[1m\x1b[32m    Finished\x1b[0m release artifacts in: '{dist_path}'");
@@ -175,7 +223,7 @@ pub(crate) fn build_all_web_assets(config: &BuildConfig) -> Result<TokenStream,
     })() {
         Ok(ts)   => Ok(ts),
         Err(err) => match err {
-            Error::IOError(msg) => return compiler_error!("Failed to build project '{}' , {msg}", config.proj_name),
+            Error::IOError(msg) => compiler_error!("Failed to build project '{}' , {msg}", config.proj_name),
             Error::CompilerError(ts) => Err(ts),
         }
     }
@@ -187,14 +235,54 @@ pub(crate) fn parse_config_attrs(attrs: TokenStream) -> Result<AttrConfig, Token
     let mut path = None;
     let mut addr = None;
     let mut watch = None;
+    let mut debounce = None;
+    let mut poll = None;
+    let mut poll_interval = None;
+    let mut compress = None;
+    let mut precompress = None;
+    let mut precompress_min_size = None;
+    let mut spa_fallback = None;
+    let mut proxy = None;
+    let mut cors_origins = None;
+    let mut cors_methods = None;
+    let mut cors_headers = None;
+    let mut include = None;
+    let mut exclude = None;
+    let mut gitignore = None;
 
     struct NoQuotesError;
     let trim_quotes = |value: &str| -> Result<String, NoQuotesError> {
-        if value.as_bytes()[0] != b'"' || value.as_bytes()[value.as_bytes().len() - 1] != b'"' { 
+        if value.as_bytes()[0] != b'"' || value.as_bytes()[value.len() - 1] != b'"' { 
             return Err(NoQuotesError);
         }
         Ok(value[1..value.len() - 1].to_string())
     };
+    // Accepts both a single string and a list of strings, like: "*.css" or ["*.css", "*.js"]
+    let parse_str_list = |value: &TokenTree| -> Result<Vec<String>, NoQuotesError> {
+        match value {
+            TokenTree::Group(group) if group.delimiter() == Delimiter::Bracket => group.stream()
+                .into_iter()
+                .filter(|tt| !matches!(tt, TokenTree::Punct(punct) if punct.as_char() == ','))
+                .map(|tt| trim_quotes(&tt.to_string()))
+                .collect(),
+            _ => Ok(vec![trim_quotes(&value.to_string())?]),
+        }
+    };
+ 
+    // Accepts a map of strings, like: { "/api": "http://127.0.0.1:3001", "/ws": "http://127.0.0.1:3002" }
+    let parse_str_map = |value: &TokenTree| -> Result<Vec<(String, String)>, NoQuotesError> {
+        let TokenTree::Group(group) = value else { return Err(NoQuotesError) };
+        if group.delimiter() != Delimiter::Brace { return Err(NoQuotesError) };
+        let tokens: Vec<TokenTree> = group.stream().into_iter().collect();
+        tokens.split(|tt| matches!(tt, TokenTree::Punct(punct) if punct.as_char() == ','))
+            .filter(|entry| !entry.is_empty())
+            .map(|entry| match entry {
+                [key, TokenTree::Punct(punct), value] if punct.as_char() == ':' => 
+                    Ok((trim_quotes(&key.to_string())?, trim_quotes(&value.to_string())?)),
+                _ => Err(NoQuotesError),
+            })
+            .collect()
+    };
 
     loop {
         
@@ -217,6 +305,8 @@ pub(crate) fn parse_config_attrs(attrs: TokenStream) -> Result<AttrConfig, Token
         let value_as_str = match &value {
             TokenTree::Literal(value) => value.to_string(),
             TokenTree::Ident(value) => value.to_string(),
+            TokenTree::Group(value) if value.delimiter() == Delimiter::Bracket => value.to_string(),
+            TokenTree::Group(value) if value.delimiter() == Delimiter::Brace => value.to_string(),
             _ => return compiler_error!(value, "Unexpected token: '{value}'"),
         };
 
@@ -225,28 +315,122 @@ pub(crate) fn parse_config_attrs(attrs: TokenStream) -> Result<AttrConfig, Token
                 let Ok(val) = trim_quotes(&value_as_str) else {
                     return compiler_error!(value, "Unable to parse addr, {value} is not a `&str`");                    
                 };
-                addr = Some(Attr::new(val, Some(value.into())));
+                addr = Some(Attr::new(val, Some(value)));
             },
             "path" => { 
                 let Ok(val) = trim_quotes(&value_as_str) else {
                     return compiler_error!(value, "Unable to parse path, {value} is not a `&str`");
                 };
-                path = Some(Attr::new(val, Some(value.into())));
+                path = Some(Attr::new(val, Some(value)));
             },
             "port" => { 
                 let Ok(val) = value_as_str.parse() else { 
                     return compiler_error!(value, "Unable to parse port, {value} is not a u16");
                 };
-                port = Some(Attr::new(val, Some(value.into())));
+                port = Some(Attr::new(val, Some(value)));
             },
             "watch" => {
                 let Ok(val) = value_as_str.parse() else { 
                     return compiler_error!(value, "Unable to parse watch, {value} is not boolean");
                 };
-                watch = Some(Attr::new(val, Some(value.into())));
+                watch = Some(Attr::new(val, Some(value)));
+            }
+            "debounce" => {
+                let Ok(val) = value_as_str.parse() else { 
+                    return compiler_error!(value, "Unable to parse debounce, {value} is not a duration in milliseconds");
+                };
+                debounce = Some(Attr::new(val, Some(value)));
+            }
+            "poll" => {
+                let Ok(val) = value_as_str.parse() else { 
+                    return compiler_error!(value, "Unable to parse poll, {value} is not boolean");
+                };
+                poll = Some(Attr::new(val, Some(value)));
+            }
+            "poll_interval" => {
+                let Ok(val) = value_as_str.parse() else { 
+                    return compiler_error!(value, "Unable to parse poll_interval, {value} is not a duration in milliseconds");
+                };
+                poll_interval = Some(Attr::new(val, Some(value)));
+            }
+            "compress" => {
+                let Ok(val) = value_as_str.parse() else { 
+                    return compiler_error!(value, "Unable to parse compress, {value} is not boolean");
+                };
+                compress = Some(Attr::new(val, Some(value)));
+            }
+            "precompress" => {
+                let Ok(val) = value_as_str.parse() else { 
+                    return compiler_error!(value, "Unable to parse precompress, {value} is not boolean");
+                };
+                precompress = Some(Attr::new(val, Some(value)));
+            }
+            "precompress_min_size" => {
+                let Ok(val) = value_as_str.parse() else { 
+                    return compiler_error!(value, "Unable to parse precompress_min_size, {value} is not a size in bytes");
+                };
+                precompress_min_size = Some(Attr::new(val, Some(value)));
+            }
+            "spa_fallback" => {
+                let Ok(val) = value_as_str.parse() else { 
+                    return compiler_error!(value, "Unable to parse spa_fallback, {value} is not boolean");
+                };
+                spa_fallback = Some(Attr::new(val, Some(value)));
+            }
+            "proxy" => {
+                let Ok(val) = parse_str_map(&value) else { 
+                    return compiler_error!(value, "Unable to parse proxy, {value} is not a map like {{ \"/api\": \"http://127.0.0.1:3001\" }}");
+                };
+                if let Some((_, upstream)) = val.iter().find(|(_, upstream)| !upstream.starts_with("http://")) {
+                    return compiler_error!(value, "Unable to parse proxy, '{upstream}' is not a plain http url like \"http://127.0.0.1:3001\"");
+                }
+                if let Some((path, _)) = val.iter().find(|(path, _)| !path.starts_with('/')) {
+                    return compiler_error!(value, "Unable to parse proxy, path '{path}' does not start with '/'");
+                }
+                proxy = Some(Attr::new(val, Some(value)));
+            }
+            "cors_origins" => {
+                let Ok(val) = parse_str_list(&value) else { 
+                    return compiler_error!(value, "Unable to parse cors_origins, {value} is not a `&str` or a list of `&str`");
+                };
+                cors_origins = Some(Attr::new(val, Some(value)));
+            }
+            "cors_methods" => {
+                let Ok(val) = parse_str_list(&value) else { 
+                    return compiler_error!(value, "Unable to parse cors_methods, {value} is not a `&str` or a list of `&str`");
+                };
+                let methods = ["GET", "HEAD", "PUT", "POST", "DELETE", "OPTIONS"];
+                if let Some(method) = val.iter().find(|method| !methods.contains(&method.as_str())) {
+                    return compiler_error!(value, "Unable to parse cors_methods, '{method}' is not one of {methods:?}");
+                }
+                cors_methods = Some(Attr::new(val, Some(value)));
+            }
+            "cors_headers" => {
+                let Ok(val) = parse_str_list(&value) else { 
+                    return compiler_error!(value, "Unable to parse cors_headers, {value} is not a `&str` or a list of `&str`");
+                };
+                cors_headers = Some(Attr::new(val, Some(value)));
+            }
+            "include" => {
+                let Ok(val) = parse_str_list(&value) else { 
+                    return compiler_error!(value, "Unable to parse include, {value} is not a `&str` or a list of `&str`");
+                };
+                include = Some(Attr::new(val, Some(value)));
+            }
+            "exclude" => {
+                let Ok(val) = parse_str_list(&value) else { 
+                    return compiler_error!(value, "Unable to parse exclude, {value} is not a `&str` or a list of `&str`");
+                };
+                exclude = Some(Attr::new(val, Some(value)));
+            }
+            "gitignore" => {
+                let Ok(val) = value_as_str.parse() else { 
+                    return compiler_error!(value, "Unable to parse gitignore, {value} is not boolean");
+                };
+                gitignore = Some(Attr::new(val, Some(value)));
             }
             i  => { 
-                return compiler_error!(ident, "Unknown attribute: '{i}', help: available attributes are: 'addr', 'path', 'port' and 'watch'");
+                return compiler_error!(ident, "Unknown attribute: '{i}', help: available attributes are: 'addr', 'path', 'port', 'watch', 'debounce', 'poll', 'poll_interval', 'compress', 'precompress', 'precompress_min_size', 'spa_fallback', 'proxy', 'cors_origins', 'cors_methods', 'cors_headers', 'include', 'exclude' and 'gitignore'");
             },
         }
 
@@ -256,10 +440,31 @@ pub(crate) fn parse_config_attrs(attrs: TokenStream) -> Result<AttrConfig, Token
             Some(tt) => return compiler_error!(tt, "Unexpected character '{tt}', help: use ',' to separate attributes."),
         }
     };
+    // Like WASMDEV_POLL_INTERVAL, setting an interval enables polling.
+    let poll = match (poll, &poll_interval) {
+        (Some(Attr { value: false, tt: Some(tt) }), Some(_)) =>
+            return compiler_error!(tt, "poll_interval is set, but poll is false, help: remove poll_interval, or poll."),
+        (None, Some(_)) => Some(Attr::new(true, None)),
+        (poll, _) => poll,
+    };
     Ok(AttrConfig { 
         port: port.unwrap_or(Attr::new(8080, None)), 
         path: path.unwrap_or(Attr::new("src".into(), None)), 
         addr: addr.unwrap_or(Attr::new("127.0.0.1".into(), None)),
         watch: watch.unwrap_or(Attr::new(true, None)),
+        debounce: debounce.unwrap_or(Attr::new(100, None)),
+        poll: poll.unwrap_or(Attr::new(false, None)),
+        poll_interval: poll_interval.unwrap_or(Attr::new(500, None)),
+        compress: compress.unwrap_or(Attr::new(true, None)),
+        precompress: precompress.unwrap_or(Attr::new(false, None)),
+        precompress_min_size: precompress_min_size.unwrap_or(Attr::new(1024, None)),
+        spa_fallback: spa_fallback.unwrap_or(Attr::new(false, None)),
+        proxy: proxy.unwrap_or(Attr::new(vec![], None)),
+        cors_origins: cors_origins.unwrap_or(Attr::new(vec![], None)),
+        cors_methods: cors_methods.unwrap_or(Attr::new(vec!["GET".into(), "HEAD".into()], None)),
+        cors_headers: cors_headers.unwrap_or(Attr::new(vec![], None)),
+        include: include.unwrap_or(Attr::new(vec![], None)),
+        exclude: exclude.unwrap_or(Attr::new(vec![], None)),
+        gitignore: gitignore.unwrap_or(Attr::new(true, None)),
     })
 }
\ No newline at end of file
@@ -3,14 +3,14 @@ use proc_macro2::{TokenStream, TokenTree, Ident, Punct, Spacing, Group, Delimite
 pub(crate) fn get_fn_name(func: &TokenStream) -> Option<TokenTree> {
     let mut it = func.clone().into_iter().skip_while(|tt| {
         let TokenTree::Ident(ident) = tt else { return true };
-        ident.to_string() != "fn"
+        *ident != "fn"
     });
     it.next(); // Skip "fn" identifier
     it.next()  // Should be function name identifier
 }
 
 pub(crate) fn emit_compilation_error(msg: &str, span: &Span) -> TokenStream {
-    let span = span.clone();
+    let span = *span;
     TokenStream::from_iter(vec![
         TokenTree::Ident(Ident::new("compile_error", span)),
         TokenTree::Punct({
@@ -5,8 +5,46 @@ init("/index.wasm");
 var protocol = window.location.protocol === 'https:' ? 'wss:' : 'ws:';
 var url = protocol + "//" + window.location.host;
 
+var overlay;
+
+var hide_build_errors = () => {
+    if (overlay) overlay.remove();
+    overlay = null;
+}
+
+var show_build_errors = diagnostics => {
+    hide_build_errors();
+    overlay = document.createElement("div");
+    overlay.style.cssText = "position:fixed;inset:0;z-index:2147483647;overflow:auto;padding:2em;" +
+        "background:rgba(0,0,0,.9);color:#e8e8e8;font:13px/1.5 monospace";
+    var close = document.createElement("button");
+    close.textContent = "\u00d7";
+    close.title = "Dismiss (Esc)";
+    close.style.cssText = "float:right;font-size:2em;background:none;border:none;color:inherit;cursor:pointer";
+    close.onclick = hide_build_errors;
+    var title = document.createElement("h2");
+    title.textContent = "Failed to build wasm target";
+    title.style.color = "#ff5555";
+    overlay.append(close, title);
+    for (var diagnostic of diagnostics) {
+        var file_location = document.createElement("div");
+        file_location.style.color = "#8be9fd";
+        if (diagnostic.file) file_location.textContent = diagnostic.file + ":" + diagnostic.line + ":" + diagnostic.column;
+        var text = document.createElement("pre");
+        text.textContent = (diagnostic.rendered || diagnostic.level + ": " + diagnostic.message).replace(/\x1b\[[0-9;]*m/g, "");
+        overlay.append(file_location, text);
+    }
+    document.documentElement.appendChild(overlay);
+}
+
+document.addEventListener("keydown", e => { if (e.key === "Escape") hide_build_errors() });
+
 var on_msg = msg => {
-    if(!msg || !msg.data || !(msg.data + "").startsWith("reload ")) return;
+    if(!msg || !msg.data) return;
+    var data = msg.data + "";
+    if (data.startsWith("build-error ")) return show_build_errors(JSON.parse(data.slice("build-error ".length)));
+    if (data === "build-ok") return hide_build_errors();
+    if(!data.startsWith("reload ")) return;
     var path = msg.data.split(" ")[1];
     if(!path) return;
     if (path.includes("index.wasm") || path.includes("index.html")){
@@ -26,11 +64,45 @@ var on_msg = msg => {
     }
 }
 
+var ws;
+// Output from before the first connection, like logs while the app starts. Null once connected,
+// after that output is dropped while the dev server is gone, since the page reloads when it is back.
+var pending = [];
+var max_pending = 100;
+
+var send = msg => {
+    if (ws && ws.readyState === WebSocket.OPEN) ws.send(msg);
+    else if (pending && pending.length < max_pending) pending.push(msg);
+}
+
+var format_arg = arg => {
+    if (typeof arg === "string") return arg;
+    if (arg instanceof Error) return arg.stack || (arg + "");
+    try { return JSON.stringify(arg); } catch (_) { return arg + ""; }
+}
+
+// Forward console output to the terminal running the dev server
+for (var level of ["log", "info", "debug", "warn", "error"]) {
+    ((level, original) => console[level] = (...args) => {
+        original.apply(console, args);
+        var text = args.map(format_arg).join(" ");
+        // Rust panics are reported through console.error by console_error_panic_hook
+        var tag = level === "error" && text.includes("panicked at") ? "panic" : level;
+        send("console " + tag + " " + text);
+    })(level, console[level]);
+}
+window.addEventListener("error", e => send("console error " + (e.error ? format_arg(e.error) : e.message)));
+window.addEventListener("unhandledrejection", e => send("console error Uncaught (in promise) " + format_arg(e.reason)));
+
 var reconnect = () => window.setTimeout(open_websocket, 5000);
 
 var open_websocket = init => {
-    var ws = new WebSocket(url);
-    if (!init) ws.onopen = () => window.location.reload();
+    ws = new WebSocket(url);
+    ws.onopen = () => {
+        if (!init) return window.location.reload();
+        for (var msg of pending) ws.send(msg);
+        pending = null;
+    };
     ws.onmessage = msg => on_msg(msg);
     ws.onclose = reconnect;
 }
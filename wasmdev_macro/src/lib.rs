@@ -24,9 +24,44 @@ pub(crate) use wasmdev_core as core;
 /// * **watch**: Reload assets on file-system changes
 ///   - Default: true
 ///   - Note: **Only affects debug build**, always false for release build
+/// * **debounce**: Milliseconds to wait for more file-system changes before reloading
+///   - Default: 100
+/// * **poll**: Scan for file-system changes instead of using OS notifications.
+///   Use for network filesystems or container volumes where notifications don't arrive.
+///   - Default: false
+///   - Note: Can also be enabled with env var `WASMDEV_POLL=true`
+/// * **poll_interval**: Milliseconds between scans when polling, setting it enables polling
+///   - Default: 500
+///   - Note: Can also be set with env var `WASMDEV_POLL_INTERVAL`, which enables polling
+/// * **compress**: Compress text and wasm responses with brotli or gzip, if the browser supports it
+///   - Default: true
+/// * **precompress**: Write `.gz` and `.br` siblings of compressible assets in the release dist
+///   - Default: false
+///   - Note: The server serves these siblings when it runs in release mode
+/// * **precompress_min_size**: Smallest file size in bytes to write precompressed siblings for
+///   - Default: 1024
+/// * **spa_fallback**: Serve index.html for navigations to paths that are not a file, like "/settings/profile".
+///   Use for single page apps with client side routing.
+///   - Default: false
+/// * **proxy**: Forward requests to backend servers, like `{ "/api": "http://127.0.0.1:3001" }`.
+///   Paths below a proxied path, like "/api/users", are forwarded too. WebSocket connections are supported.
+///   - Default: no proxy
+/// * **cors_origins**: Origin or list of origins of apps that may load responses, like `"http://localhost:6006"`.
+///   Use `"*"` to allow any origin.
+///   - Default: none, only the same origin is allowed
+/// * **cors_methods**: Method or list of methods that apps on **cors_origins** may use
+///   - Default: `["GET", "HEAD"]`
+/// * **cors_headers**: Request header or list of request headers that apps on **cors_origins** may send, or `"*"` for any header
+///   - Default: none
+/// * **include**: Glob pattern or list of glob patterns of static assets to serve
+///   - Default: every file in **path**
+/// * **exclude**: Glob pattern or list of glob patterns of static assets to not serve or watch
+///   - Default: none, `*.rs`, `.git`, `.gitignore` and editor temp files are always excluded
+/// * **gitignore**: Don't serve or watch files ignored by `.gitignore` files
+///   - Default: true
 /// 
 /// ### Usage
-/// ```rust
+/// ```rust,ignore
 /// // src/main.rs
 /// #[wasmdev::main]
 /// fn main() {
@@ -44,14 +79,14 @@ pub(crate) use wasmdev_core as core;
 /// cargo run # No extra targets or tools required
 /// ```
 /// ### Example: Manually specifying static asset directory
-/// ```rust
+/// ```rust,ignore
 /// #[wasmdev::main(path: "www")]
 /// fn main() {
 /// // ...
 /// }
 /// ```
 /// File tree:
-/// ```
+/// ```text
 /// ├── Cargo.toml
 /// ├── src
 /// │   └── main.rs
@@ -59,7 +94,7 @@ pub(crate) use wasmdev_core as core;
 ///     └── index.html
 /// ```
 /// ### Example: Allow external devices to run app
-/// ```rust
+/// ```rust,ignore
 /// // This allows all traffic through the firewall, use with extreme care
 /// #[wasmdev::main(addr: "0.0.0.0")]
 /// fn main() {
@@ -111,10 +146,26 @@ fn make_server_main_fn(wasm_main_fn: &TokenStream, config: AttrConfig) -> Result
     let address             = &config.attrs.addr.value;
     let port                = &config.attrs.port.value;
     let watch               = &config.attrs.watch.value;
+    let debounce            = &config.attrs.debounce.value;
+    let poll                = &config.attrs.poll.value;
+    let poll_interval       = &config.attrs.poll_interval.value;
+    let compress            = &config.attrs.compress.value;
+    let precompress         = &config.attrs.precompress.value;
+    let spa_fallback        = &config.attrs.spa_fallback.value;
+    let proxy_paths         = config.attrs.proxy.value.iter().map(|(path, _)| path);
+    let proxy_upstreams     = config.attrs.proxy.value.iter().map(|(_, upstream)| upstream);
+    let cors_origins        = &config.attrs.cors_origins.value;
+    let cors_methods        = &config.attrs.cors_methods.value;
+    let cors_headers        = &config.attrs.cors_headers.value;
+    let dist_path           = &config.dist_path;
+    let gitignore           = &config.attrs.gitignore.value;
+    let static_include      = &config.static_include;
+    let static_exclude      = &config.static_exclude;
     let wasm_path           = &config.wasm_path;
     let index_js_path       = &config.index_js_path;
     let index_wasm_path     = &config.index_wasm_path;
     let proj_html_path      = &config.proj_html_path;
+    let proj_manifest_path  = &config.proj_manifest_path;
     let proj_src_path       = &config.proj_src_path;
     let proj_static_path    = &config.proj_static_path;
 
@@ -129,6 +180,13 @@ fn make_server_main_fn(wasm_main_fn: &TokenStream, config: AttrConfig) -> Result
         return compiler_error!(span, "Error: Unable to read directory: {}", proj_static_path);
     };
 
+    // Check that include and exclude patterns are valid globs:
+    if let Err(err) = config.static_file_filter() {
+        let tt = if config.attrs.exclude.tt.is_some() { &config.attrs.exclude.tt } else { &config.attrs.include.tt };
+        let span = tt.as_ref().map(|tt| tt.span()).unwrap_or(Span::call_site());
+        return compiler_error!(span, "Error: Invalid include or exclude pattern, {}", err);
+    };
+
     // Check that provided ip address is an ip address:
     let Ok(_) = address.parse::<std::net::IpAddr>() else {
         let span = config.attrs.addr.tt.map(|tt| tt.span()).unwrap_or(Span::call_site());
@@ -154,12 +212,12 @@ fn make_server_main_fn(wasm_main_fn: &TokenStream, config: AttrConfig) -> Result
             // Scope all this in order to not pollute main fn scope.
             {
                 use std::net::TcpListener;
-                use std::path::PathBuf;
                 use std::str::from_utf8;
+                use std::time::Duration;
                 use std::fs;
                 use wasmdev::prelude::*;
                 use wasmdev::{Server, ServerConfig};
-                use wasmdev::{core, utils::make_watcher};
+                use wasmdev::{core, utils::{make_watcher, make_paths_watcher, print_client_message, FileEvent, RestartableTask, WatchBackend, WatchConfig}};
 
                 let is_release       = #is_release;
                 let index_html       = #index_html;
@@ -168,12 +226,34 @@ fn make_server_main_fn(wasm_main_fn: &TokenStream, config: AttrConfig) -> Result
                 let address          = #address;
                 let port             = #port;
                 let watch            = #watch;
+                let debounce         = #debounce;
+                let poll             = #poll;
+                let poll_interval    = #poll_interval;
+                let compress         = #compress;
+                let precompress      = #precompress;
+                let spa_fallback     = #spa_fallback;
+                let cors_origins: &[&str] = &[#(#cors_origins),*];
+                let cors_methods: &[&str] = &[#(#cors_methods),*];
+                let cors_headers: &[&str] = &[#(#cors_headers),*];
+                let dist_path        = #dist_path;
                 let wasm_path        = #wasm_path;
                 let index_js_path    = #index_js_path;
                 let index_wasm_path  = #index_wasm_path;
                 let proj_html_path   = #proj_html_path;
+                let proj_manifest_path = #proj_manifest_path;
                 let proj_src_path    = #proj_src_path;
                 let proj_static_path = #proj_static_path;
+                let gitignore        = #gitignore;
+
+                // Decides which files are served as static assets, and which file changes to react to:
+                let static_file_filter = std::sync::Arc::new(
+                    core::fs::FileFilter::new(proj_static_path, &[#(#static_include),*], &[#(#static_exclude),*], gitignore)
+                        .expect("Invalid include or exclude pattern")
+                );
+                let src_file_filter = std::sync::Arc::new(
+                    core::fs::FileFilter::new(proj_src_path, &[], &[], gitignore)
+                        .expect("Unable to read .gitignore files")
+                );
 
                 // Make sure that release build includes the latest versions of static assets:
                 #static_asset_cache
@@ -190,16 +270,55 @@ fn make_server_main_fn(wasm_main_fn: &TokenStream, config: AttrConfig) -> Result
                         .on_get_request("/index.html")
                         .set_response_body(index_html.as_bytes().to_vec())
                         .build();
+                    config.on_websocket_message(print_client_message);
+                    config.set_compression(compress);
+                    for (path, upstream) in [#((#proxy_paths, #proxy_upstreams)),*] {
+                        config
+                            .on_get_request(path)
+                            .proxy(upstream)
+                            .build();
+                    }
+                    if spa_fallback {
+                        config.set_navigation_fallback(Some("/index.html"));
+                    }
+                    if is_release && precompress {
+                        config.serve_precompressed(dist_path);
+                    }
+                    if !cors_origins.is_empty() {
+                        let to_strings = |list: &[&str]| list.iter().map(|s| s.to_string()).collect();
+                        config.set_cors(Some(wasmdev::http::Cors {
+                            origins: to_strings(cors_origins),
+                            methods: to_strings(cors_methods),
+                            headers: to_strings(cors_headers),
+                        }));
+                    }
                 });
 
                 let build_load_and_serve_app = {
                     let mut server = server.clone();
-                    move || -> Option<()>{
+                    move |cancel: &core::code::CancelToken| -> Option<()>{
                         eprintln!("\x1b[0m\x1b[0m\x1b[1m\x1b[32m    Building\x1b[0m wasm target");
-                        let _         = core::code::build_wasm(wasm_path, is_release, target_path)?;
+                        let report_build_error = |err: core::code::BuildError| {
+                            // A newer build has been started, so this error is not relevant.
+                            if let core::code::BuildError::Cancelled = err { return };
+                            eprintln!("\x1b[0m\x1b[0m\x1b[1m\x1b[31m       Error\x1b[0m {}", err.summary());
+                            let diagnostics = core::code::diagnostics_to_json(&err.diagnostics());
+                            // Clients that connect later, like a reloaded page, get the error too.
+                            server.broadcast_retained(format!("build-error {}", diagnostics).as_bytes());
+                        };
+                        if let Err(err) = core::code::build_wasm_cancellable(wasm_path, is_release, target_path, cancel) {
+                            report_build_error(err);
+                            return None;
+                        }
                         let wasm_code = fs::read(&index_wasm_path).ok()?;
                         let js_code   = fs::read(&index_js_path).ok()?;
-                        let js_code   = if is_release { core::code::minify_javascript(&js_code)? } else { js_code };
+                        let js_code   = if is_release {
+                            match core::code::minify_javascript(&js_code) {
+                                Ok(js_code) => js_code,
+                                Err(err) => { report_build_error(err); return None },
+                            }
+                        } else { js_code };
+                        server.broadcast_retained("build-ok".as_bytes());
                         let code_did_update = server.configure(|config| {
                             config
                                 .on_get_request("/index.js")
@@ -222,7 +341,7 @@ fn make_server_main_fn(wasm_main_fn: &TokenStream, config: AttrConfig) -> Result
                     path.replace(proj_static_path, "").replace("\\", "/");
 
                 let serve_static_files = || {
-                    let file_paths = core::fs::list_files_recursively(proj_static_path)
+                    let file_paths = static_file_filter.list_files(proj_static_path)
                         .expect(&format!("Unable to list static assets: '{}'", proj_static_path));
                     let file_and_req_path_iter = file_paths.iter()
                         .filter_map(|file_path| file_path.to_str())
@@ -240,23 +359,61 @@ fn make_server_main_fn(wasm_main_fn: &TokenStream, config: AttrConfig) -> Result
                     }
                 };
                 
-                let load_and_serve_file = {
+                // Size and modification time of each served file, to tell if an event actually changed it.
+                let file_version = |path: &std::path::Path| fs::metadata(path).ok().map(|meta| (meta.len(), meta.modified().ok()));
+
+                let mut load_and_serve_file = {
                     let mut server = server.clone();
-                    move |paths: Vec<PathBuf> | {
-                        for file_path in paths {
+                    let static_file_filter = static_file_filter.clone();
+                    let mut file_versions: std::collections::HashMap<String, _> = static_file_filter.list_files(proj_static_path)
+                        .unwrap_or_default().iter()
+                        .filter_map(|path| Some((file_path_to_req_path(path.to_str()?), file_version(path)?)))
+                        .collect();
+                    move |events: Vec<FileEvent>| {
+                        let mut removed_paths = vec![];
+                        let mut updated_paths = vec![];
+                        for event in events {
+                            match event {
+                                FileEvent::Created(path) | FileEvent::Modified(path) => updated_paths.push(path),
+                                FileEvent::Removed(path) => removed_paths.push(path),
+                                FileEvent::Renamed(from, to) => { removed_paths.push(from); updated_paths.push(to); },
+                            }
+                        }
+                        for file_path in removed_paths {
+                            let Some(req_path) = file_path.to_str().map(file_path_to_req_path) else { continue };
+                            if req_path == "/index.html" { continue }; // index.html is handled in another watcher, so skip it.
+                            // A removed path might be a directory, so remove everything below it as well.
+                            let removed_req_paths = server.configure(|config| {
+                                let mut req_paths = config.remove_endpoints_in_dir(&req_path);
+                                if config.remove_endpoint(&req_path) { req_paths.push(req_path) };
+                                req_paths
+                            });
+                            for req_path in removed_req_paths {
+                                file_versions.remove(&req_path);
+                                eprintln!("\x1b[0m\x1b[0m\x1b[1m\x1b[32m     Removed\x1b[0m {}", req_path);
+                                server.broadcast(format!("reload {}", req_path).as_bytes());
+                            }
+                        }
+                        // A created or renamed path might be a directory, so serve all files inside of it.
+                        let file_paths = updated_paths.into_iter().flat_map(|path| 
+                            if path.is_dir() { static_file_filter.list_files(&path).unwrap_or_default() } else { vec![path] }
+                        );
+                        for file_path in file_paths {
                             let file_path = file_path.as_path();
                             let Some(req_path) = file_path.to_str().map(file_path_to_req_path) else { continue };
                             if req_path == "/index.html" { continue }; // index.html is handled in another watcher, so skip it.
-                            let Ok(file_contents) = fs::read(file_path) else { continue };
-                            let file_did_update = server.configure(|config| config
+                            let Some(version) = file_version(file_path) else { continue };
+                            if file_versions.get(&req_path) == Some(&version) { continue };
+                            file_versions.insert(req_path.clone(), version);
+                            let Some(file_path) = file_path.to_str() else { continue };
+                            // Loaded on request like at startup, so large files are streamed and keep their Last-Modified.
+                            server.configure(|config| config
                                 .on_get_request(&req_path)
-                                .set_response_body(file_contents)
+                                .lazy_load(file_path)
                                 .build()
                             );
-                            if file_did_update {
-                                eprintln!("\x1b[0m\x1b[0m\x1b[1m\x1b[32m     Serving\x1b[0m {}", req_path);
-                                server.broadcast(format!("reload {}", req_path).as_bytes());
-                            }
+                            eprintln!("\x1b[0m\x1b[0m\x1b[1m\x1b[32m     Serving\x1b[0m {}", req_path);
+                            server.broadcast(format!("reload {}", req_path).as_bytes());
                         }
                     }
                 };
@@ -282,16 +439,56 @@ fn make_server_main_fn(wasm_main_fn: &TokenStream, config: AttrConfig) -> Result
                 // Load server resources:
                 serve_static_files();
                 load_and_serve_index_html();
-                build_load_and_serve_app();
+                build_load_and_serve_app(&core::code::CancelToken::new());
 
-                let _watchers = if watch { Some((
-                    make_watcher(&proj_static_path, move |paths| { load_and_serve_file(paths); })
-                        .expect("Unable to watch static files folder, required for hot-reload when updated."),
-                    make_watcher(&proj_src_path,    move |_|     { build_load_and_serve_app(); })
-                        .expect("Unable to watch src folder, required for hot-reload."),
-                    make_watcher(&proj_html_path,   move |_|     { load_and_serve_index_html(); }),
-                        // Providing a custom index.html is optional, so open watcher is allowed to fail silently here.
-                ))} else { None };
+                let debounce            = Duration::from_millis(debounce);
+                let backend             = if poll { WatchBackend::Poll(Duration::from_millis(poll_interval)) } else { WatchBackend::Native };
+                let watch_config        = WatchConfig { debounce, backend, filter: None }.with_env_overrides();
+                let static_watch_config = WatchConfig { filter: Some(static_file_filter), ..watch_config.clone() };
+                let src_watch_config    = WatchConfig { filter: Some(src_file_filter), ..watch_config.clone() };
+                let html_watch_config   = watch_config.clone();
+                let deps_watch_config   = watch_config;
+                // Both the native watcher and polling failed. Keep serving, just without hot-reload for that folder.
+                let warn_not_watched = |name: &str, path: &str| eprintln!(
+                    "\x1b[0m\x1b[0m\x1b[1m\x1b[33m     Warning\x1b[0m Unable to watch {} '{}', hot-reload is disabled for it", name, path
+                );
+                let _watchers = if watch { 
+                    // Changes during a build cancels it and starts a new one.
+                    let rebuild_app = RestartableTask::new(move |cancel| { build_load_and_serve_app(cancel); });
+                    // Cargo.toml, build scripts and local crates that the app depends on. They are resolved again when
+                    // a Cargo.toml changes, since a path dependency might have been added. That runs on a thread of its own,
+                    // which replaces the watcher.
+                    let (resolve_again, resolve_requests) = std::sync::mpsc::channel::<()>();
+                    let rebuild_app_on_dependency_change = rebuild_app.clone();
+                    std::thread::spawn(move || loop {
+                        let build_dependency_paths = core::code::build_dependency_paths(proj_manifest_path).unwrap_or_else(|err| {
+                            eprintln!("\x1b[0m\x1b[0m\x1b[1m\x1b[33m     Warning\x1b[0m Unable to resolve build dependencies, {}", err);
+                            vec![proj_manifest_path.into()]
+                        });
+                        let _deps_watcher = make_paths_watcher(&build_dependency_paths, &deps_watch_config, {
+                            let resolve_again = resolve_again.clone();
+                            let rebuild_app = rebuild_app_on_dependency_change.clone();
+                            move |events: Vec<FileEvent>| {
+                                let mut paths = events.iter().flat_map(|event| event.paths());
+                                if paths.any(|path| path.file_name().is_some_and(|name| name == "Cargo.toml")) {
+                                    let _ = resolve_again.send(());
+                                }
+                                rebuild_app.restart();
+                            }
+                        });
+                        // Rebuilding on changes to dependencies is best effort, so open watcher is allowed to fail silently here.
+                        let Ok(_) = resolve_requests.recv() else { return };
+                        while resolve_requests.try_recv().is_ok() {}
+                    });
+                    Some((
+                        make_watcher(&proj_static_path, &static_watch_config, move |events| { load_and_serve_file(events); })
+                            .or_else(|| { warn_not_watched("static files folder", proj_static_path); None }),
+                        make_watcher(&proj_src_path,    &src_watch_config,    move |_| { rebuild_app.restart(); })
+                            .or_else(|| { warn_not_watched("src folder", proj_src_path); None }),
+                        make_watcher(&proj_html_path,   &html_watch_config,   move |_|      { load_and_serve_index_html(); }),
+                            // Providing a custom index.html is optional, so open watcher is allowed to fail silently here.
+                    ))
+                } else { None };
 
                 let addr = format!("{}:{}", address, port);
                 let Ok(tcp_socket) = TcpListener::bind(addr) else { 
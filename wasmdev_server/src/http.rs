@@ -1,15 +1,25 @@
+mod body;
+mod connection;
+mod cors;
 mod error;
 mod header;
 mod helpers;
+mod hub;
+mod proxy;
 mod request;
 mod response;
 mod server;
 mod status_code;
 mod version;
+pub mod websocket;
 
+pub use self::body::*;
+pub use self::connection::*;
+pub use self::cors::*;
 pub use self::error::*;
 pub use self::header::*;
 pub use self::helpers::*;
+pub use self::proxy::*;
 pub use self::request::*;
 pub use self::response::*;
 pub use self::server::*;
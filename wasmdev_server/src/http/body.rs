@@ -0,0 +1,101 @@
+use std::borrow::Cow;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::sync::Arc;
+
+/// Body of a response. Small bodies are kept in memory, large files are streamed from disk.
+pub enum Body<'a> {
+    /// Bytes owned by the response, or borrowed from an endpoint
+    Bytes(Cow<'a, [u8]>),
+    /// Part of bytes shared with other responses, like a buffer that a request handler keeps around
+    Shared { bytes: Arc<[u8]>, offset: usize, len: usize },
+    /// Part of a file, read in chunks while it is written to the client
+    File { file: File, offset: u64, len: u64 },
+}
+
+impl<'a> Body<'a> {
+    /// Stream the whole file.
+    pub fn from_file(file: File) -> io::Result<Body<'static>> {
+        let len = file.metadata()?.len();
+        Ok(Body::File { file, offset: 0, len })
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            Body::Bytes(bytes) => bytes.len(),
+            Body::Shared { len, .. } => *len,
+            Body::File { len, .. } => *len as usize,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The bytes of the body, unless it is streamed.
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Body::Bytes(bytes) => Some(bytes),
+            Body::Shared { bytes, offset, len } => Some(&bytes[*offset..*offset + *len]),
+            Body::File { .. } => None,
+        }
+    }
+
+    /// The bytes from first to last position, inclusive. Streamed files are still streamed.
+    pub fn slice(self, first: u64, last: u64) -> Body<'a> {
+        let range = first as usize..=last as usize;
+        match self {
+            Body::Bytes(Cow::Borrowed(bytes)) => Body::Bytes(Cow::Borrowed(&bytes[range])),
+            Body::Bytes(Cow::Owned(bytes)) => Body::Bytes(Cow::Owned(bytes[range].to_vec())),
+            Body::Shared { bytes, offset, .. } => Body::Shared { bytes, offset: offset + first as usize, len: (last - first + 1) as usize },
+            Body::File { file, offset, .. } => Body::File { file, offset: offset + first, len: last - first + 1 },
+        }
+    }
+
+    /// Write the body. Files are copied in chunks, so they are never fully loaded into memory.
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        match self {
+            Body::Bytes(bytes) => writer.write_all(bytes),
+            Body::Shared { bytes, offset, len } => writer.write_all(&bytes[*offset..*offset + *len]),
+            Body::File { file, offset, len } => {
+                let mut file = file;
+                file.seek(SeekFrom::Start(*offset))?;
+                let copied = io::copy(&mut file.take(*len), writer)?;
+                // The file was truncated after Content-Length was sent, so the response can't be completed.
+                if copied < *len { return Err(io::ErrorKind::UnexpectedEof.into()) };
+                Ok(())
+            },
+        }
+    }
+}
+
+impl<'a> From<&'a [u8]> for Body<'a> {
+    fn from(bytes: &'a [u8]) -> Self {
+        Body::Bytes(Cow::Borrowed(bytes))
+    }
+}
+
+impl From<Vec<u8>> for Body<'_> {
+    fn from(bytes: Vec<u8>) -> Self {
+        Body::Bytes(Cow::Owned(bytes))
+    }
+}
+
+impl<'a> From<&'a str> for Body<'a> {
+    fn from(s: &'a str) -> Self {
+        Body::Bytes(Cow::Borrowed(s.as_bytes()))
+    }
+}
+
+impl From<String> for Body<'_> {
+    fn from(s: String) -> Self {
+        Body::Bytes(Cow::Owned(s.into_bytes()))
+    }
+}
+
+impl From<Arc<[u8]>> for Body<'_> {
+    fn from(bytes: Arc<[u8]>) -> Self {
+        let len = bytes.len();
+        Body::Shared { bytes, offset: 0, len }
+    }
+}
@@ -0,0 +1,44 @@
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::time::Duration;
+
+/// A connection to a client that the server can read requests from and write responses to.
+/// TcpStream is the real thing, test_support::MemoryConnection serves requests without binding a port.
+pub trait Connection: Read + Write + Send + Sized + 'static {
+    /// Another handle to the same connection, so that it can be read and written from different places.
+    fn try_clone(&self) -> io::Result<Self>;
+    /// Address of the client, given to the WebSocket message handler.
+    fn peer_addr(&self) -> io::Result<SocketAddr>;
+    /// Make reads fail with WouldBlock or TimedOut after timeout, for all handles. None blocks forever.
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+    /// Close both directions, for all handles.
+    fn shutdown(&self) -> io::Result<()>;
+    /// Close the direction to the client, for all handles. Reading still works.
+    fn shutdown_write(&self) -> io::Result<()>;
+    /// The TcpStream, if this is one. Upgraded TcpStreams are polled by the WebSocket hub,
+    /// other connections get a thread each that reads frames for it.
+    fn into_tcp_stream(self) -> std::result::Result<TcpStream, Self> {
+        Err(self)
+    }
+}
+
+impl Connection for TcpStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        TcpStream::try_clone(self)
+    }
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        TcpStream::peer_addr(self)
+    }
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+    fn shutdown(&self) -> io::Result<()> {
+        TcpStream::shutdown(self, Shutdown::Both)
+    }
+    fn shutdown_write(&self) -> io::Result<()> {
+        TcpStream::shutdown(self, Shutdown::Write)
+    }
+    fn into_tcp_stream(self) -> std::result::Result<TcpStream, Self> {
+        Ok(self)
+    }
+}
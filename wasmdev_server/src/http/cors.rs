@@ -0,0 +1,41 @@
+use super::{Request, Header};
+
+/// Cross-origin resource sharing settings, see the "CORS protocol" in the Fetch standard.
+/// Allows an app on another origin, like "http://localhost:6006", to load responses from the server.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Cors {
+    /// Origins that may read responses, like "http://localhost:6006", or "*" for any origin
+    pub origins: Vec<String>,
+    /// Methods that cross-origin requests may use, like "GET" and "POST"
+    pub methods: Vec<String>,
+    /// Request headers that cross-origin requests may send, or "*" for any header
+    pub headers: Vec<String>,
+}
+
+impl Cors {
+    fn allows_any_origin(&self) -> bool {
+        self.origins.iter().any(|origin| origin == "*")
+    }
+
+    /// Headers to add to the response to request. Preflight requests also get the allowed methods and headers.
+    pub fn response_headers(&self, request: &Request) -> Vec<Header> {
+        let mut headers = vec![];
+        // The response depends on the origin of the request, unless any origin is allowed.
+        if !self.allows_any_origin() { headers.push(Header::vary("Origin")) };
+        let Some(origin) = request.headers().find_map(|h| match h { Header::Origin(origin) => Some(origin), _ => None }) else {
+            return headers;
+        };
+        match self.allows_any_origin() {
+            true => headers.push(Header::access_control_allow_origin("*")),
+            false if self.origins.contains(origin) => headers.push(Header::access_control_allow_origin(origin)),
+            false => return headers,
+        }
+        if request.is_preflight() {
+            headers.push(Header::access_control_allow_methods(&self.methods.join(", ")));
+            if !self.headers.is_empty() {
+                headers.push(Header::access_control_allow_headers(&self.headers.join(", ")));
+            }
+        }
+        headers
+    }
+}
@@ -2,6 +2,8 @@ use std::fmt;
 use std::num::ParseIntError;
 use std::str::Utf8Error;
 
+use super::StatusCode;
+
 pub type Result<T> = core::result::Result<T, Error>;
 
 #[derive(Debug, Clone)]
@@ -30,6 +32,25 @@ pub enum HttpErrorKind{
     IncompleteReqError(String),
     UnsupportedReqTypeError,
     UnsupportedVersionError,
+    UriTooLong,
+    HeadersTooLarge,
+    BodyTooLarge(u64),
+    RequestTimeout,
+}
+
+impl HttpErrorKind {
+    /// Status code of the response to a request that failed with this error
+    pub fn status_code(&self) -> StatusCode {
+        match &self {
+            Self::ParseError(_) | Self::IncompleteReqError(_) => StatusCode(400),
+            Self::RequestTimeout => StatusCode(408),
+            Self::BodyTooLarge(_) => StatusCode(413),
+            Self::UriTooLong => StatusCode(414),
+            Self::HeadersTooLarge => StatusCode(431),
+            Self::UnsupportedReqTypeError => StatusCode(501),
+            Self::UnsupportedVersionError => StatusCode(505),
+        }
+    }
 }
 
 impl fmt::Display for HttpErrorKind {
@@ -43,7 +64,15 @@ impl fmt::Display for HttpErrorKind {
             Self::UnsupportedVersionError =>
                 write!(f, "Unsupported http version. Only v 1.0 and 1.1 is supported"),
             Self::UnsupportedReqTypeError =>
-                write!(f, "Unsupported requst type. GET, POST, PUT and DELETE is supported."),
+                write!(f, "Unsupported requst type. GET, HEAD, POST, PUT, DELETE and OPTIONS is supported."),
+            Self::UriTooLong =>
+                write!(f, "Request line is too long"),
+            Self::HeadersTooLarge =>
+                write!(f, "Request headers are too large or too many"),
+            Self::BodyTooLarge(len) =>
+                write!(f, "Request body too large: {len} bytes"),
+            Self::RequestTimeout =>
+                write!(f, "Request did not arrive in time"),
         }
     }
 }
@@ -62,12 +91,51 @@ impl fmt::Display for BufferErrorKind {
     }
 }
 
+#[derive(Debug, Clone)]
+pub enum WebSocketErrorKind{
+    UnknownOpcode(u8),
+    ReservedBits(u8),
+    UnmaskedFrame,
+    InvalidControlFrame,
+    InvalidCloseFrame,
+    InvalidFragmentation,
+    MessageTooLarge(u64),
+    InvalidUtf8,
+}
+
+impl WebSocketErrorKind {
+    /// Status code to send in the close frame, see RFC 6455 section 7.4.1
+    pub fn close_code(&self) -> u16 {
+        match &self {
+            Self::MessageTooLarge(_) => 1009,
+            Self::InvalidUtf8 => 1007,
+            _ => 1002,
+        }
+    }
+}
+
+impl fmt::Display for WebSocketErrorKind {
+    #[inline]
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self {
+            Self::UnknownOpcode(opcode) => write!(f, "Unknown websocket opcode: {opcode:#x}"),
+            Self::ReservedBits(bits) => write!(f, "Websocket frame has reserved bits set without an extension: {bits:#05b}"),
+            Self::UnmaskedFrame => write!(f, "Websocket frame from client is not masked"),
+            Self::InvalidControlFrame => write!(f, "Websocket control frame is fragmented or too large"),
+            Self::InvalidCloseFrame => write!(f, "Websocket close frame has an invalid status code"),
+            Self::InvalidFragmentation => write!(f, "Websocket message fragments are out of order"),
+            Self::MessageTooLarge(len) => write!(f, "Websocket message too large: {len} bytes"),
+            Self::InvalidUtf8 => write!(f, "Websocket text message is not utf8 encoded"),
+        }
+    }
+}
 
 #[derive(Debug, Clone)]
 pub enum Error{
     IOError(std::io::ErrorKind),
     HttpError(HttpErrorKind),
     BufferError(BufferErrorKind),
+    WebSocketError(WebSocketErrorKind),
 }
 
 impl fmt::Display for Error {
@@ -77,6 +145,7 @@ impl fmt::Display for Error {
             Self::IOError(error_kind) => error_kind.fmt(f),
             Self::HttpError(error_kind) => error_kind.fmt(f),
             Self::BufferError(error_kind) => error_kind.fmt(f),
+            Self::WebSocketError(error_kind) => error_kind.fmt(f),
         }
     }
 }
@@ -111,6 +180,12 @@ impl From<BufferErrorKind> for Error {
     }
 }
 
+impl From<WebSocketErrorKind> for Error {
+    fn from(value: WebSocketErrorKind) -> Self {
+        Error::WebSocketError(value)
+    }
+}
+
 impl From<HttpErrorKind> for Error {
     fn from(value: HttpErrorKind) -> Self {
         Error::HttpError(value)
@@ -1,7 +1,9 @@
 use std::fmt;
 use std::str::FromStr;
+use std::time::SystemTime;
 
 use super::error::*;
+use super::{format_http_date, parse_http_date};
 
 #[derive(Clone, PartialEq)]
 pub enum Header{
@@ -13,8 +15,31 @@ pub enum Header{
     SecWebSocketExtensions(String),
     SecWebSocketAccept(String),
     ContentLength(usize),
+    TransferEncoding(String),
+    Expect(String),
     ContentType(String),
-    Unsupported,
+    CacheControl(String),
+    ETag(String),
+    IfNoneMatch(String),
+    LastModified(SystemTime),
+    IfModifiedSince(SystemTime),
+    Accept(String),
+    AcceptEncoding(String),
+    ContentEncoding(String),
+    Vary(String),
+    Allow(String),
+    AcceptRanges(String),
+    Range(Vec<ByteRange>),
+    IfRange(String),
+    ContentRange(String),
+    Origin(String),
+    AccessControlRequestMethod(String),
+    AccessControlRequestHeaders(String),
+    AccessControlAllowOrigin(String),
+    AccessControlAllowMethods(String),
+    AccessControlAllowHeaders(String),
+    /// Any other header, as name and value
+    Other(String, String),
 }
 impl Header{
     pub fn host (s: &str) -> Header { Header::Host(s.into()) }
@@ -25,7 +50,85 @@ impl Header{
     pub fn sec_websocket_extensions (s: &str) -> Header { Header::SecWebSocketExtensions(s.into()) }
     pub fn sec_websocket_accept (s: &str) -> Header { Header::SecWebSocketAccept(s.into()) }
     pub fn content_length (s: &str) -> Result<Header> { Ok(Header::ContentLength(s.parse()?))}
+    pub fn transfer_encoding (s: &str) -> Header { Header::TransferEncoding(s.into())}
+    pub fn expect (s: &str) -> Header { Header::Expect(s.into())}
     pub fn content_type (s: &str) -> Header { Header::ContentType(s.into())}
+    pub fn cache_control (s: &str) -> Header { Header::CacheControl(s.into())}
+    pub fn etag (s: &str) -> Header { Header::ETag(s.into())}
+    pub fn if_none_match (s: &str) -> Header { Header::IfNoneMatch(s.into())}
+    pub fn last_modified (s: &str) -> Result<Header> { Ok(Header::LastModified(parse_date(s)?))}
+    pub fn if_modified_since (s: &str) -> Result<Header> { Ok(Header::IfModifiedSince(parse_date(s)?))}
+    pub fn accept (s: &str) -> Header { Header::Accept(s.into())}
+    pub fn accept_encoding (s: &str) -> Header { Header::AcceptEncoding(s.into())}
+    pub fn content_encoding (s: &str) -> Header { Header::ContentEncoding(s.into())}
+    pub fn vary (s: &str) -> Header { Header::Vary(s.into())}
+    pub fn allow (s: &str) -> Header { Header::Allow(s.into())}
+    pub fn accept_ranges (s: &str) -> Header { Header::AcceptRanges(s.into())}
+    pub fn range (s: &str) -> Result<Header> { Ok(Header::Range(parse_ranges(s)?))}
+    pub fn if_range (s: &str) -> Header { Header::IfRange(s.into())}
+    pub fn content_range (s: &str) -> Header { Header::ContentRange(s.into())}
+    pub fn origin (s: &str) -> Header { Header::Origin(s.into())}
+    pub fn access_control_request_method (s: &str) -> Header { Header::AccessControlRequestMethod(s.into())}
+    pub fn access_control_request_headers (s: &str) -> Header { Header::AccessControlRequestHeaders(s.into())}
+    pub fn access_control_allow_origin (s: &str) -> Header { Header::AccessControlAllowOrigin(s.into())}
+    pub fn access_control_allow_methods (s: &str) -> Header { Header::AccessControlAllowMethods(s.into())}
+    pub fn access_control_allow_headers (s: &str) -> Header { Header::AccessControlAllowHeaders(s.into())}
+    pub fn other (name: &str, value: &str) -> Header { Header::Other(name.into(), value.into())}
+}
+
+fn parse_date(s: &str) -> Result<SystemTime> {
+    parse_http_date(s).ok_or(Error::format_error(format!("Unable to parse date '{s}'")))
+}
+
+/// A range of bytes in a "Range" header, see RFC 9110 section 14.1.2
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ByteRange {
+    /// First and last position, like "0-499". The last position is None for "500-", which ends at the end.
+    FromTo(u64, Option<u64>),
+    /// The last n bytes, like "-500"
+    Suffix(u64),
+}
+impl ByteRange {
+    /// First and last position in a body of len bytes, or None if no part of the range is in the body.
+    pub fn resolve(&self, len: u64) -> Option<(u64, u64)> {
+        match *self {
+            _ if len == 0 => None,
+            ByteRange::FromTo(first, _) if first >= len => None,
+            ByteRange::FromTo(first, last) => Some((first, last.unwrap_or(len - 1).min(len - 1))),
+            ByteRange::Suffix(0) => None,
+            ByteRange::Suffix(n) => Some((len.saturating_sub(n), len - 1)),
+        }
+    }
+}
+impl fmt::Display for ByteRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ByteRange::FromTo(first, Some(last)) => write!(f, "{}-{}", first, last),
+            ByteRange::FromTo(first, None) => write!(f, "{}-", first),
+            ByteRange::Suffix(n) => write!(f, "-{}", n),
+        }
+    }
+}
+
+/// Parse a range set like "bytes=0-499, -500"
+fn parse_ranges(s: &str) -> Result<Vec<ByteRange>> {
+    let parse = || {
+        let (unit, ranges) = s.split_once('=')?;
+        if !unit.trim().eq_ignore_ascii_case("bytes") { return None };
+        ranges.split(',').map(|range| {
+            let (first, last) = range.trim().split_once('-')?;
+            match (first, last) {
+                ("", n) => Some(ByteRange::Suffix(n.parse().ok()?)),
+                (first, "") => Some(ByteRange::FromTo(first.parse().ok()?, None)),
+                (first, last) => {
+                    let (first, last) = (first.parse().ok()?, last.parse().ok()?);
+                    if first > last { return None };
+                    Some(ByteRange::FromTo(first, Some(last)))
+                },
+            }
+        }).collect()
+    };
+    parse().ok_or(Error::format_error(format!("Unable to parse range '{s}'")))
 }
 
 impl fmt::Display for Header {
@@ -39,8 +142,33 @@ impl fmt::Display for Header {
             Header::SecWebSocketExtensions(s) => write!(f, "Sec-WebSocket-Extensions: {}", s),
             Header::SecWebSocketAccept(s) => write!(f, "Sec-WebSocket-Accept: {}", s),
             Header::ContentLength(s) => write!(f, "Content-Length: {}", s),
+            Header::TransferEncoding(s) => write!(f, "Transfer-Encoding: {}", s),
+            Header::Expect(s) => write!(f, "Expect: {}", s),
             Header::ContentType(s) => write!(f, "Content-Type: {}", s),
-            Header::Unsupported => write!(f, "Unsupported Header"),
+            Header::CacheControl(s) => write!(f, "Cache-Control: {}", s),
+            Header::ETag(s) => write!(f, "ETag: {}", s),
+            Header::IfNoneMatch(s) => write!(f, "If-None-Match: {}", s),
+            Header::LastModified(t) => write!(f, "Last-Modified: {}", format_http_date(*t)),
+            Header::IfModifiedSince(t) => write!(f, "If-Modified-Since: {}", format_http_date(*t)),
+            Header::Accept(s) => write!(f, "Accept: {}", s),
+            Header::AcceptEncoding(s) => write!(f, "Accept-Encoding: {}", s),
+            Header::ContentEncoding(s) => write!(f, "Content-Encoding: {}", s),
+            Header::Vary(s) => write!(f, "Vary: {}", s),
+            Header::Allow(s) => write!(f, "Allow: {}", s),
+            Header::AcceptRanges(s) => write!(f, "Accept-Ranges: {}", s),
+            Header::Range(ranges) => {
+                let ranges: Vec<String> = ranges.iter().map(ByteRange::to_string).collect();
+                write!(f, "Range: bytes={}", ranges.join(", "))
+            },
+            Header::IfRange(s) => write!(f, "If-Range: {}", s),
+            Header::ContentRange(s) => write!(f, "Content-Range: {}", s),
+            Header::Origin(s) => write!(f, "Origin: {}", s),
+            Header::AccessControlRequestMethod(s) => write!(f, "Access-Control-Request-Method: {}", s),
+            Header::AccessControlRequestHeaders(s) => write!(f, "Access-Control-Request-Headers: {}", s),
+            Header::AccessControlAllowOrigin(s) => write!(f, "Access-Control-Allow-Origin: {}", s),
+            Header::AccessControlAllowMethods(s) => write!(f, "Access-Control-Allow-Methods: {}", s),
+            Header::AccessControlAllowHeaders(s) => write!(f, "Access-Control-Allow-Headers: {}", s),
+            Header::Other(name, value) => write!(f, "{}: {}", name, value),
         }
     }
 }
@@ -52,6 +180,10 @@ impl FromStr for Header{
         let (header, value) = s.split_at(s.find(':')
             .ok_or(Error::format_error(format!("Unable to parse header '{s}'")))?);
         let value = &value[1..].trim();
+        // Whitespace in or around the name is not allowed, see RFC 9112 section 5.1
+        if header.is_empty() || header.contains(char::is_whitespace) {
+            return Err(Error::format_error(format!("Invalid header name in '{s}'")));
+        }
         let parse_version = |v : &str | v.parse::<i32>();
         match header.to_ascii_lowercase().as_str() {
             "host" => Ok(Header::host(value)),
@@ -62,14 +194,38 @@ impl FromStr for Header{
             "sec-websocket-extensions" => Ok(Header::sec_websocket_extensions(value)),
             "sec-websocket-accept" => Ok(Header::sec_websocket_accept(value)),
             "content-length" => Ok(Header::content_length(value)?),
+            "transfer-encoding" => Ok(Header::transfer_encoding(value)),
+            "expect" => Ok(Header::expect(value)),
             "content-type" => Ok(Header::content_type(value)),
-            _ => Ok(Header::Unsupported)
+            "cache-control" => Ok(Header::cache_control(value)),
+            "etag" => Ok(Header::etag(value)),
+            "if-none-match" => Ok(Header::if_none_match(value)),
+            // Invalid dates are ignored rather than rejected, see RFC 9110 section 13.1.3
+            "last-modified" => Ok(Header::last_modified(value).unwrap_or_else(|_| Header::other(header, value))),
+            "if-modified-since" => Ok(Header::if_modified_since(value).unwrap_or_else(|_| Header::other(header, value))),
+            "accept" => Ok(Header::accept(value)),
+            "accept-encoding" => Ok(Header::accept_encoding(value)),
+            "content-encoding" => Ok(Header::content_encoding(value)),
+            "vary" => Ok(Header::vary(value)),
+            "allow" => Ok(Header::allow(value)),
+            "accept-ranges" => Ok(Header::accept_ranges(value)),
+            // Invalid ranges are ignored rather than rejected, see RFC 9110 section 14.2
+            "range" => Ok(Header::range(value).unwrap_or_else(|_| Header::other(header, value))),
+            "if-range" => Ok(Header::if_range(value)),
+            "content-range" => Ok(Header::content_range(value)),
+            "origin" => Ok(Header::origin(value)),
+            "access-control-request-method" => Ok(Header::access_control_request_method(value)),
+            "access-control-request-headers" => Ok(Header::access_control_request_headers(value)),
+            "access-control-allow-origin" => Ok(Header::access_control_allow_origin(value)),
+            "access-control-allow-methods" => Ok(Header::access_control_allow_methods(value)),
+            "access-control-allow-headers" => Ok(Header::access_control_allow_headers(value)),
+            _ => Ok(Header::other(header, value))
         }
     }
 }
 
-pub fn write_headers(f: &mut fmt::Formatter, headers: &Vec<Header>) -> fmt::Result {
-    if headers.len() == 0 { 
+pub fn write_headers(f: &mut fmt::Formatter, headers: &[Header]) -> fmt::Result {
+    if headers.is_empty() { 
         write!(f, "")
     } else {
         headers.iter().try_fold((), |_, header| { 
@@ -1,11 +1,16 @@
-use std::io::{BufWriter, Read};
+use std::fs;
+use std::io::{self, BufWriter, Read};
 use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
 use std::str::from_utf8;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use sha1::{Sha1, Digest};
 use base64::Engine;
 
 use super::error::*;
-use super::{Request, Response, Header, Version, StatusCode};
+use super::{Body, ByteRange, Request, RequestType, Response, Header, Version, StatusCode};
+use crate::utils::hash_bytes;
+use wasmdev_core::compress::{sibling_path, Encoding};
 
 
 pub fn is_valid_websocket(request: &Request) -> bool {
@@ -16,12 +21,12 @@ pub fn is_valid_websocket(request: &Request) -> bool {
 
 pub fn compute_accept(websocket_key_header: &str) -> String {
     let mut hasher = Sha1::new();
-    hasher.update(&websocket_key_header);
+    hasher.update(websocket_key_header);
     hasher.update("258EAFA5-E914-47DA-95CA-C5AB0DC85B11");
     base64::engine::general_purpose::STANDARD.encode(hasher.finalize())
 }
 
-pub fn make_websocket_accept_response(request: &Request) -> Result<Response> {
+pub fn make_websocket_accept_response(request: &Request) -> Result<Response<'_>> {
     request.headers
         .iter()
         .find_map(|header| {
@@ -40,7 +45,21 @@ pub fn make_websocket_accept_response(request: &Request) -> Result<Response> {
         }).ok_or(Error::incomplete_req_error("Missing header: 'SecWebSocketKey'"))
 }
 
-pub fn make_http_response(status_code: StatusCode, headers: Vec<Header>, body: Option<&Vec<u8>>) -> Response {
+/// Guess if a request is a browser navigation, like a reload of a client side route.
+/// These are GET requests for a path without file extension, or that accept html.
+pub fn is_navigation(request: &Request) -> bool {
+    if !matches!(request.request_type, RequestType::GET) { return false };
+    let path = request.path.split('?').next().unwrap_or_default();
+    let has_extension = path.rsplit('/').next().is_some_and(|name| name.contains('.'));
+    let accepts_html = request.headers().any(|h| matches!(h, Header::Accept(s) if s.contains("text/html")));
+    !has_extension || accepts_html
+}
+
+/// The Content-Length header is added if missing, so that the response can be followed by others on the same connection.
+pub fn make_http_response(status_code: StatusCode, mut headers: Vec<Header>, body: Option<Body<'_>>) -> Response<'_> {
+    if status_code.allows_body() && !headers.iter().any(|h| matches!(h, Header::ContentLength(_))) {
+        headers.push(Header::ContentLength(body.as_ref().map(Body::len).unwrap_or(0)));
+    }
     Response {
         version: Version::V1_1,
         status_code, 
@@ -49,32 +68,446 @@ pub fn make_http_response(status_code: StatusCode, headers: Vec<Header>, body: O
     }
 }
 
+/// Longest request line accepted, longer ones get 414 URI Too Long.
+pub const MAX_REQUEST_LINE_LEN: usize = 8 * 1024;
+/// Most bytes accepted for all header lines together, more get 431 Request Header Fields Too Large.
+pub const MAX_HEADERS_LEN: usize = 64 * 1024;
+/// Most header lines accepted, more get 431 Request Header Fields Too Large.
+pub const MAX_HEADER_COUNT: usize = 100;
+
+/// Read a request head, which might arrive in several pieces, up to and including the empty line that ends it.
+/// Reads that time out give RequestTimeout.
 pub fn parse_request<T: Read>(reader: &mut BufReader<T>) -> Result<Request>{
-    reader.fill_buf()?;
+    reader.fill_buf().map_err(timeout_error)?;
     if reader.buffer().is_empty() { return Err(BufferErrorKind::EmptyBuffer.into()) };
-    let Some(end_index) = reader.buffer()
-        .windows(4)
-        .enumerate()
-        .find_map(|(i, range)| {
-            let [a, b, c, d] = range else { return None };
-            if *a == b'\r' && *b == b'\n' && *c == b'\r' && *d == b'\n' { 
-                Some(i) // Dont include end-of-message \r\n\r\n
-            } else { None }
-        }) else { return Err(ParseErrorKind::IncompleteError.into()) };
-        
-    let msg = from_utf8(&reader.buffer()[0..end_index])?;
-    let request: Request = msg.parse()?;
-    reader.consume(end_index + 4); // Consume end-of-message
-    Ok(request)
+    let mut head = vec![];
+    loop {
+        read_head_line(reader, &mut head, MAX_REQUEST_LINE_LEN, HttpErrorKind::UriTooLong)?;
+        // Empty lines before the request line should be ignored, see RFC 9112 section 2.2
+        if head != b"\r\n" { break };
+        head.clear();
+    }
+    let headers_start = head.len();
+    let mut header_count = 0;
+    loop {
+        let line_start = head.len();
+        // The empty line at the end does not count towards the limit.
+        let limit = MAX_HEADERS_LEN.saturating_sub(line_start - headers_start) + 2;
+        read_head_line(reader, &mut head, limit, HttpErrorKind::HeadersTooLarge)?;
+        if head[line_start..] == *b"\r\n" { break };
+        header_count += 1;
+        if header_count > MAX_HEADER_COUNT { return Err(HttpErrorKind::HeadersTooLarge.into()) };
+    }
+    let msg = from_utf8(&head[..head.len() - 4])?; // Dont include end-of-message \r\n\r\n
+    msg.parse()
+}
+
+/// Append a line to head. Lines longer than limit give the too_long error.
+fn read_head_line<T: Read>(reader: &mut BufReader<T>, head: &mut Vec<u8>, limit: usize, too_long: HttpErrorKind) -> Result<()> {
+    let len = reader.by_ref().take(limit as u64).read_until(b'\n', head).map_err(timeout_error)?;
+    match len {
+        0 => Err(ParseErrorKind::IncompleteError.into()),
+        _ if head.ends_with(b"\n") => Ok(()),
+        _ if len == limit => Err(too_long.into()),
+        _ => Err(ParseErrorKind::IncompleteError.into()),
+    }
+}
+
+fn timeout_error(err: io::Error) -> Error {
+    match err.kind() {
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => HttpErrorKind::RequestTimeout.into(),
+        _ => err.into(),
+    }
+}
+
+/// Largest request body that is read by default, see ServerConfig::set_max_body_len
+pub const DEFAULT_MAX_BODY_LEN: usize = 16 * 1024 * 1024;
+/// Longest line accepted in a chunked body, like a chunk size or a trailer field.
+const MAX_CHUNK_LINE_LEN: u64 = 8 * 1024;
+
+/// Read the body of a request, sent with either Content-Length or chunked transfer encoding.
+/// Bodies larger than max_len are not read, and give a BodyTooLarge error. Reads that time out give RequestTimeout.
+pub fn read_body<T: Read>(reader: &mut BufReader<T>, request: &mut Request, max_len: usize) -> Result<()> {
+    if request.is_chunked() {
+        request.body = read_chunked_body(reader, max_len)?;
+    } else if let Some(len) = request.content_length() {
+        if len > max_len { return Err(HttpErrorKind::BodyTooLarge(len as u64).into()) };
+        let mut body = vec![0; len];
+        reader.read_exact(&mut body).map_err(timeout_error)?;
+        request.body = body;
+    }
+    Ok(())
+}
+
+/// Read chunks until the last (empty) chunk, see RFC 9112 section 7.1
+fn read_chunked_body<T: Read>(reader: &mut BufReader<T>, max_len: usize) -> Result<Vec<u8>> {
+    let mut body = vec![];
+    loop {
+        let line = read_chunk_line(reader)?;
+        // Chunk extensions after ';' are ignored
+        let size = usize::from_str_radix(line.split(';').next().unwrap_or_default().trim(), 16)?;
+        if size == 0 { break };
+        let len = body.len().saturating_add(size);
+        if len > max_len { return Err(HttpErrorKind::BodyTooLarge(len as u64).into()) };
+        let start = body.len();
+        body.resize(len, 0);
+        reader.read_exact(&mut body[start..]).map_err(timeout_error)?;
+        if !read_chunk_line(reader)?.is_empty() { 
+            return Err(Error::format_error("chunk data is not followed by CRLF"));
+        }
+    }
+    // Trailer fields are ignored
+    while !read_chunk_line(reader)?.is_empty() {}
+    Ok(body)
+}
+
+/// Read a line, without the "\r\n" at the end.
+fn read_chunk_line<T: Read>(reader: &mut BufReader<T>) -> Result<String> {
+    let mut line = vec![];
+    reader.by_ref().take(MAX_CHUNK_LINE_LEN).read_until(b'\n', &mut line).map_err(timeout_error)?;
+    if !line.ends_with(b"\n") {
+        if line.len() as u64 == MAX_CHUNK_LINE_LEN { return Err(Error::format_error("chunk line is too long")) };
+        return Err(ParseErrorKind::IncompleteError.into());
+    }
+    let Some(line) = line.strip_suffix(b"\r\n") else { return Err(Error::format_error("chunk line does not end with CRLF")) };
+    Ok(from_utf8(line)?.to_owned())
 }
 
 pub fn write_response<T: Write>(writer: &mut BufWriter<T>, response: &Response) -> Result<()> {
     writer.write_all(response.to_string().as_bytes())?;
     if let Some(body) = &response.body {
-        writer.write_all(body)?;
-        writer.write_all("\r\n".as_bytes())?;
+        body.write_to(writer)?;
     }
     writer.flush()?;
     Ok(())
 }
 
+
+
+/// Strong entity tag for a file that is too large to hash, from its length and modification time.
+pub fn make_file_etag(len: u64, modified: SystemTime) -> String {
+    format!("\"{:x}-{:x}\"", len, modified.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos())
+}
+
+/// Strong entity tag for a body, also includes the length to make collisions less likely.
+pub fn make_etag(body: &[u8]) -> String {
+    format!("\"{:x}-{:08x}\"", body.len(), hash_bytes(body))
+}
+
+/// Check the conditional headers of a request against the validators of a response.
+/// If-None-Match takes precedence over If-Modified-Since, see RFC 9110 section 13.2.2
+pub fn is_not_modified(request: &Request, response_headers: &[Header]) -> bool {
+    let etag = response_headers.iter().find_map(|h| match h { Header::ETag(etag) => Some(etag), _ => None });
+    let last_modified = response_headers.iter().find_map(|h| match h { Header::LastModified(t) => Some(t), _ => None });
+    if let Some(if_none_match) = request.headers().find_map(|h| match h { Header::IfNoneMatch(s) => Some(s), _ => None }) {
+        let Some(etag) = etag else { return false };
+        // Weak comparison, a weak and strong tag with the same opaque value matches.
+        let opaque = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+        return if_none_match.trim() == "*" || if_none_match.split(',').any(|tag| opaque(tag) == opaque(etag));
+    }
+    let if_modified_since = request.headers().find_map(|h| match h { Header::IfModifiedSince(t) => Some(t), _ => None });
+    match (if_modified_since, last_modified) {
+        (Some(since), Some(last_modified)) => last_modified <= since,
+        _ => false,
+    }
+}
+
+/// Ranges to respond with, if the request has a "Range" header that applies to the response.
+/// Only GET requests have ranges, and "If-Range" must match the validators of the response, see RFC 9110 section 13.1.5
+pub fn requested_ranges<'a>(request: &'a Request, response_headers: &[Header]) -> Option<&'a [ByteRange]> {
+    if !matches!(request.request_type, RequestType::GET) { return None };
+    let ranges = request.headers().find_map(|h| match h { Header::Range(ranges) => Some(ranges), _ => None })?;
+    let Some(if_range) = request.headers().find_map(|h| match h { Header::IfRange(s) => Some(s.trim()), _ => None }) else {
+        return Some(ranges);
+    };
+    let is_match = if if_range.starts_with('"') {
+        // Strong comparison, weak tags never match.
+        response_headers.iter().any(|h| matches!(h, Header::ETag(etag) if etag == if_range))
+    } else {
+        let last_modified = response_headers.iter().find_map(|h| match h { Header::LastModified(t) => Some(*t), _ => None });
+        last_modified.is_some() && last_modified == parse_http_date(if_range)
+    };
+    is_match.then_some(ranges)
+}
+
+/// 206 Partial Content with the requested range of body, or 416 Range Not Satisfiable if it is outside of body.
+/// Several ranges are answered with the whole body, since multipart responses are not supported.
+pub fn make_range_response<'a>(headers: Vec<Header>, body: Body<'a>, ranges: &[ByteRange]) -> Response<'a> {
+    let len = body.len() as u64;
+    let [range] = ranges else { return make_http_response(StatusCode(200), headers, Some(body)) };
+    let Some((first, last)) = range.resolve(len) else {
+        return make_http_response(StatusCode(416), vec![Header::content_range(&format!("bytes */{len}"))], None);
+    };
+    let body = body.slice(first, last);
+    let mut headers: Vec<Header> = headers.into_iter().filter(|h| !matches!(h, Header::ContentLength(_))).collect();
+    headers.push(Header::content_range(&format!("bytes {first}-{last}/{len}")));
+    headers.push(Header::ContentLength(body.len()));
+    make_http_response(StatusCode(206), headers, Some(body))
+}
+
+const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+/// Format time as an IMF-fixdate, like: "Sun, 06 Nov 1994 08:49:37 GMT"
+pub fn format_http_date(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let days = (secs / 86400) as i64;
+    let (year, month, day) = civil_from_days(days);
+    let (hour, min, sec) = (secs % 86400 / 3600, secs % 3600 / 60, secs % 60);
+    format!("{}, {day:02} {} {year} {hour:02}:{min:02}:{sec:02} GMT", WEEKDAYS[(days % 7) as usize], MONTHS[month as usize - 1])
+}
+
+/// Parse an IMF-fixdate. The obsolete rfc850 and asctime formats are not supported.
+pub fn parse_http_date(s: &str) -> Option<SystemTime> {
+    let mut words = s.split_whitespace().skip(1); // Skip weekday
+    let day: u32 = words.next()?.parse().ok()?;
+    let month = words.next()?;
+    let month = MONTHS.iter().position(|m| *m == month)? as u32 + 1;
+    let year: i64 = words.next()?.parse().ok()?;
+    let mut time = words.next()?.split(':').map(|n| n.parse::<u64>().ok());
+    let (hour, min, sec) = (time.next()??, time.next()??, time.next()??);
+    if words.next()? != "GMT" || !(1..=31).contains(&day) || hour > 23 || min > 59 || sec > 60 { return None };
+    let days = u64::try_from(days_from_civil(year, month, day)).ok()?;
+    Some(UNIX_EPOCH + Duration::from_secs(days * 86400 + hour * 3600 + min * 60 + sec))
+}
+
+// Conversion between days since 1970-01-01 and dates in the proleptic Gregorian calendar.
+// See: http://howardhinnant.github.io/date_algorithms.html
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = if month > 2 { month - 3 } else { month + 9 } as i64;
+    let doy = (153 * mp + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+/// Bodies smaller than this are not worth compressing.
+pub const MIN_COMPRESS_LEN: usize = 1024;
+
+/// Only compress text-like content and wasm, most other formats (like images) are compressed already.
+pub fn is_compressible(headers: &[Header], body: &[u8]) -> bool {
+    if body.len() < MIN_COMPRESS_LEN { return false };
+    if headers.iter().any(|h| matches!(h, Header::ContentEncoding(_))) { return false };
+    headers.iter().any(|h| match h {
+        Header::ContentType(mime_type) => 
+            mime_type.starts_with("text/") || 
+            mime_type.starts_with("application/wasm") ||
+            mime_type.starts_with("application/javascript") ||
+            mime_type.starts_with("application/json") ||
+            mime_type.starts_with("image/svg+xml"),
+        _ => false,
+    })
+}
+
+/// Headers of an encoded representation. Length and entity tag differ from the unencoded one.
+pub fn make_encoded_headers(headers: &[Header], encoding: Encoding, encoded_body: &[u8]) -> Vec<Header> {
+    let mut headers: Vec<Header> = headers.iter()
+        .filter(|h| !matches!(h, Header::ContentLength(_)))
+        .map(|h| match h {
+            Header::ETag(etag) => Header::ETag(format!("{}-{}\"", etag.trim_end_matches('"'), encoding.name())),
+            h => h.clone(),
+        })
+        .collect();
+    headers.push(Header::ContentLength(encoded_body.len()));
+    headers.push(Header::content_encoding(encoding.name()));
+    headers
+}
+
+/// Read the precompressed sibling of the file at req_path in dir, if that file has the given entity tag.
+pub fn read_precompressed(dir: &Path, req_path: &str, etag: &str, encoding: Encoding) -> Option<Vec<u8>> {
+    let file_path = dir.join(req_path.trim_start_matches('/'));
+    if make_etag(&fs::read(&file_path).ok()?) != etag { return None };
+    fs::read(sibling_path(&file_path, encoding)).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(headers: &str) -> Request {
+        // Heads are parsed without the empty line at the end, like parse_request does.
+        format!("GET /index.wasm HTTP/1.1\r\nHost: localhost\r\n{headers}").trim_end().parse().unwrap()
+    }
+
+    #[test]
+    fn http_date_round_trip() {
+        let time = UNIX_EPOCH + Duration::from_secs(784111777);
+        assert_eq!(format_http_date(time), "Sun, 06 Nov 1994 08:49:37 GMT");
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT"), Some(time));
+        assert_eq!(format_http_date(UNIX_EPOCH), "Thu, 01 Jan 1970 00:00:00 GMT");
+        // Leap days, and the end of a leap year
+        for date in ["Tue, 29 Feb 2000 12:00:00 GMT", "Thu, 29 Feb 2024 23:59:59 GMT", "Tue, 31 Dec 2024 00:00:01 GMT"] {
+            assert_eq!(format_http_date(parse_http_date(date).unwrap()), date);
+        }
+    }
+
+    #[test]
+    fn invalid_http_dates() {
+        for date in [
+            "", "Sun, 06 Nov 1994 08:49:37", "Sun, 06 Nov 1994 08:49:37 UTC", "Sun, 32 Nov 1994 08:49:37 GMT",
+            "Sun, 06 Foo 1994 08:49:37 GMT", "Sun, 06 Nov 1994 24:00:00 GMT", "Sun, 06 Nov 1994 08:49 GMT",
+            "Wed, 31 Dec 1969 23:59:59 GMT", "Sunday, 06-Nov-94 08:49:37 GMT",
+        ] {
+            assert_eq!(parse_http_date(date), None, "{date}");
+        }
+    }
+
+    #[test]
+    fn if_none_match() {
+        let etag = make_etag(b"body");
+        let headers = [Header::ETag(etag.clone())];
+        assert!(is_not_modified(&request(&format!("If-None-Match: {etag}\r\n")), &headers));
+        assert!(is_not_modified(&request(&format!("If-None-Match: \"other\", W/{etag}\r\n")), &headers));
+        assert!(is_not_modified(&request("If-None-Match: *\r\n"), &headers));
+        assert!(!is_not_modified(&request("If-None-Match: \"other\"\r\n"), &headers));
+        assert!(!is_not_modified(&request(&format!("If-None-Match: {}\r\n", make_etag(b"other body"))), &headers));
+        assert!(!is_not_modified(&request(""), &headers));
+        // Without an ETag, nothing matches
+        assert!(!is_not_modified(&request("If-None-Match: *\r\n"), &[]));
+    }
+
+    #[test]
+    fn if_modified_since() {
+        let last_modified = parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT").unwrap();
+        let headers = [Header::LastModified(last_modified)];
+        assert!(is_not_modified(&request("If-Modified-Since: Sun, 06 Nov 1994 08:49:37 GMT\r\n"), &headers));
+        assert!(is_not_modified(&request("If-Modified-Since: Mon, 07 Nov 1994 00:00:00 GMT\r\n"), &headers));
+        assert!(!is_not_modified(&request("If-Modified-Since: Sat, 05 Nov 1994 00:00:00 GMT\r\n"), &headers));
+        assert!(!is_not_modified(&request("If-Modified-Since: Sun, 06 Nov 1994 08:49:37 GMT\r\n"), &[]));
+    }
+
+    #[test]
+    fn if_none_match_takes_precedence() {
+        let last_modified = parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT").unwrap();
+        let headers = [Header::ETag(make_etag(b"body")), Header::LastModified(last_modified)];
+        let req = request("If-None-Match: \"other\"\r\nIf-Modified-Since: Sun, 06 Nov 1994 08:49:37 GMT\r\n");
+        assert!(!is_not_modified(&req, &headers));
+    }
+
+    fn chunked_body(body: &str, max_len: usize) -> Result<Vec<u8>> {
+        read_chunked_body(&mut BufReader::new(body.as_bytes()), max_len)
+    }
+
+    fn is_format_error(result: Result<Vec<u8>>) -> bool {
+        matches!(result, Err(Error::HttpError(HttpErrorKind::ParseError(ParseErrorKind::FormatError(_)))))
+    }
+
+    #[test]
+    fn chunked_bodies() {
+        assert_eq!(chunked_body("5\r\nhello\r\n7\r\n, world\r\n0\r\n\r\n", 100).unwrap(), b"hello, world");
+        assert_eq!(chunked_body("A\r\n0123456789\r\n0\r\n\r\n", 100).unwrap(), b"0123456789");
+        assert_eq!(chunked_body("0\r\n\r\n", 100).unwrap(), b"");
+        // Data after the last chunk belongs to the next request
+        let mut reader = BufReader::new("3\r\nabc\r\n0\r\n\r\nGET / HTTP/1.1".as_bytes());
+        assert_eq!(read_chunked_body(&mut reader, 100).unwrap(), b"abc");
+        assert_eq!(reader.fill_buf().unwrap(), b"GET / HTTP/1.1");
+    }
+
+    #[test]
+    fn chunk_extensions_and_trailers() {
+        assert_eq!(chunked_body("3;name=value\r\nabc\r\n0;last\r\n\r\n", 100).unwrap(), b"abc");
+        assert_eq!(chunked_body("3\r\nabc\r\n0\r\nExpires: never\r\nX-Checksum: 1\r\n\r\n", 100).unwrap(), b"abc");
+    }
+
+    #[test]
+    fn chunked_body_max_len() {
+        assert_eq!(chunked_body("5\r\nhello\r\n5\r\nworld\r\n0\r\n\r\n", 10).unwrap(), b"helloworld");
+        let too_large = chunked_body("5\r\nhello\r\n6\r\nworld!\r\n0\r\n\r\n", 10);
+        assert!(matches!(too_large, Err(Error::HttpError(HttpErrorKind::BodyTooLarge(11)))));
+        let huge_chunk = chunked_body("ffffffffffffffff\r\n", 10);
+        assert!(matches!(huge_chunk, Err(Error::HttpError(HttpErrorKind::BodyTooLarge(_)))));
+    }
+
+    #[test]
+    fn malformed_chunked_bodies() {
+        assert!(matches!(chunked_body("x\r\nabc\r\n0\r\n\r\n", 100), Err(Error::HttpError(HttpErrorKind::ParseError(ParseErrorKind::IntError(_))))));
+        assert!(is_format_error(chunked_body("3\nabc\r\n0\r\n\r\n", 100)));
+        assert!(is_format_error(chunked_body("3\r\nabcd\r\n0\r\n\r\n", 100)));
+        let long_line = format!("3;{}\r\nabc\r\n0\r\n\r\n", "x".repeat(MAX_CHUNK_LINE_LEN as usize));
+        assert!(is_format_error(chunked_body(&long_line, 100)));
+    }
+
+    #[test]
+    fn incomplete_chunked_bodies() {
+        for body in ["", "3\r\nab", "3\r\nabc\r\n", "3\r\nabc\r\n0\r\n", "3\r\nabc\r\n0\r\nExpires: never"] {
+            let result = chunked_body(body, 100);
+            let is_incomplete = matches!(result, Err(Error::HttpError(HttpErrorKind::ParseError(ParseErrorKind::IncompleteError))));
+            assert!(is_incomplete || matches!(result, Err(Error::IOError(_))), "{body:?}");
+        }
+    }
+
+    const BODY: &[u8] = b"0123456789";
+
+    /// Status, Content-Range and body of the response to a request with headers for BODY.
+    fn range_response(headers: &str) -> (i32, Option<String>, Vec<u8>) {
+        let last_modified = parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT").unwrap();
+        let response_headers = vec![Header::ETag(make_etag(BODY)), Header::LastModified(last_modified)];
+        let req = request(headers);
+        let resp = match requested_ranges(&req, &response_headers) {
+            Some(ranges) => make_range_response(response_headers, BODY.into(), ranges),
+            None => make_http_response(StatusCode(200), response_headers, Some(BODY.into())),
+        };
+        let content_range = resp.headers.iter().find_map(|h| match h { Header::ContentRange(s) => Some(s.clone()), _ => None });
+        (resp.status_code.0, content_range, resp.body.and_then(|body| body.as_bytes().map(<[u8]>::to_vec)).unwrap_or_default())
+    }
+
+    #[test]
+    fn byte_ranges() {
+        assert_eq!(range_response("Range: bytes=2-4\r\n"), (206, Some("bytes 2-4/10".into()), b"234".to_vec()));
+        assert_eq!(range_response("Range: bytes=8-20\r\n"), (206, Some("bytes 8-9/10".into()), b"89".to_vec()));
+        assert_eq!(range_response("Range: bytes=7-\r\n"), (206, Some("bytes 7-9/10".into()), b"789".to_vec()));
+        assert_eq!(range_response("Range: bytes=-3\r\n"), (206, Some("bytes 7-9/10".into()), b"789".to_vec()));
+        assert_eq!(range_response("Range: bytes=-20\r\n"), (206, Some("bytes 0-9/10".into()), BODY.to_vec()));
+        assert_eq!(range_response(""), (200, None, BODY.to_vec()));
+    }
+
+    #[test]
+    fn multiple_ranges_get_the_whole_body() {
+        assert_eq!(range_response("Range: bytes=0-1, 5-6\r\n"), (200, None, BODY.to_vec()));
+    }
+
+    #[test]
+    fn unsatisfiable_ranges() {
+        assert_eq!(range_response("Range: bytes=10-\r\n"), (416, Some("bytes */10".into()), vec![]));
+        assert_eq!(range_response("Range: bytes=-0\r\n"), (416, Some("bytes */10".into()), vec![]));
+        let resp = make_range_response(vec![], Vec::new().into(), &[ByteRange::FromTo(0, None)]);
+        assert_eq!(resp.status_code.0, 416);
+    }
+
+    #[test]
+    fn if_range() {
+        let etag = make_etag(BODY);
+        let partial = (206, Some("bytes 0-1/10".into()), b"01".to_vec());
+        let whole = (200, None, BODY.to_vec());
+        assert_eq!(range_response(&format!("Range: bytes=0-1\r\nIf-Range: {etag}\r\n")), partial);
+        assert_eq!(range_response("Range: bytes=0-1\r\nIf-Range: Sun, 06 Nov 1994 08:49:37 GMT\r\n"), partial);
+        // A mismatch, or a weak tag, means that the client has an older version and needs the whole body.
+        assert_eq!(range_response("Range: bytes=0-1\r\nIf-Range: \"other\"\r\n"), whole);
+        assert_eq!(range_response(&format!("Range: bytes=0-1\r\nIf-Range: W/{etag}\r\n")), whole);
+        assert_eq!(range_response("Range: bytes=0-1\r\nIf-Range: Mon, 07 Nov 1994 08:49:37 GMT\r\n"), whole);
+    }
+
+    #[test]
+    fn shared_body_ranges_are_not_copied() {
+        let bytes: std::sync::Arc<[u8]> = BODY.into();
+        let resp = make_range_response(vec![], bytes.clone().into(), &[ByteRange::FromTo(2, Some(4))]);
+        let Some(Body::Shared { bytes: shared, .. }) = &resp.body else { panic!("body is not shared") };
+        assert!(std::sync::Arc::ptr_eq(shared, &bytes));
+        assert_eq!(resp.body.as_ref().and_then(Body::as_bytes), Some(&b"234"[..]));
+    }
+}
@@ -0,0 +1,482 @@
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::{self, Shutdown, SocketAddr};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::thread;
+
+use mio::net::TcpStream;
+use mio::{Events, Interest, Poll, Registry, Token, Waker};
+
+use super::error::*;
+use super::Connection;
+use super::websocket::{frame_len, read_frame, write_message, Frame, Message, MessageAssembler, Opcode, MAX_MESSAGE_LEN};
+
+const WAKER: Token = Token(0);
+/// Set on the token of the upstream end of a tunnel. The client end has the same token without it.
+const UPSTREAM: usize = 1 << (usize::BITS - 1);
+/// Largest frame to wait for, a frame header and the largest message. Larger frames are read right away to reject them.
+const MAX_FRAME_LEN: u64 = MAX_MESSAGE_LEN + 14;
+/// Clients that fall this far behind on reading what is sent to them are disconnected.
+const MAX_QUEUED_LEN: usize = 16 * 1024 * 1024;
+
+enum Command {
+    /// An upgraded connection, with the bytes that were already read past the handshake
+    Add(Token, Stream, SocketAddr, Vec<u8>),
+    /// A proxied connection, once its request has been forwarded upstream
+    Tunnel(Token, Tunnel),
+    /// Bytes read from a piped connection, empty once it is closed
+    Received(Token, Vec<u8>),
+    /// An encoded frame to send to all clients
+    Send(Vec<u8>),
+    /// An encoded frame to send to all clients, and to clients that are added later
+    Retain(Vec<u8>),
+}
+
+enum Stream {
+    /// A socket, polled for reads and writes
+    Polled(TcpStream),
+    /// Any other connection, written from the hub and read by a thread of its own
+    Piped(Box<dyn PipedStream>),
+}
+
+trait PipedStream: Write + Send {
+    fn shutdown(&self);
+}
+
+impl<C: Connection> PipedStream for C {
+    fn shutdown(&self) {
+        let _ = Connection::shutdown(self);
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Polled(stream) => stream.write(buf),
+            Stream::Piped(stream) => stream.write(buf).and_then(|len| { stream.flush()?; Ok(len) }),
+        }
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Polled(stream) => stream.flush(),
+            Stream::Piped(stream) => stream.flush(),
+        }
+    }
+}
+
+/// Serves all WebSocket connections from a single thread, so that quiet clients don't keep a thread each.
+/// Proxied connections are passed on to and from upstream here as well, once their request has been forwarded.
+/// Sockets are multiplexed with mio, writes are queued and sent whenever a socket can take more data.
+#[derive(Clone)]
+pub(crate) struct WebSocketHub {
+    commands: Sender<Command>,
+    waker: Arc<Waker>,
+    next_token: Arc<AtomicUsize>,
+}
+
+impl WebSocketHub {
+    /// Start the hub thread. on_message is called on that thread, for each message from a client.
+    pub(crate) fn start(on_message: impl Fn(SocketAddr, Message) + Send + 'static) -> io::Result<Self> {
+        let poll = Poll::new()?;
+        let waker = Arc::new(Waker::new(poll.registry(), WAKER)?);
+        let (commands, receiver) = mpsc::channel();
+        thread::spawn(move || run(poll, receiver, on_message));
+        let next_token = Arc::new(AtomicUsize::new(WAKER.0 + 1));
+        Ok(WebSocketHub { commands, waker, next_token })
+    }
+
+    /// Hand over a connection that has been upgraded to WebSocket.
+    pub(crate) fn add_client<C: Connection>(&self, connection: C, addr: SocketAddr, buffered: Vec<u8>) -> io::Result<()> {
+        let token = Token(self.next_token.fetch_add(1, Ordering::Relaxed));
+        self.add(token, connection, |stream| Command::Add(token, stream, addr, buffered))
+    }
+
+    /// Hand over a proxied connection and its connection to upstream, after the request has been forwarded.
+    /// The response is passed on to the client until upstream closes. If the connection was upgraded, bytes from
+    /// the client are passed on to upstream as well, starting with those that were already read past the request.
+    pub(crate) fn add_tunnel<C: Connection>(&self, connection: C, upstream: net::TcpStream, upgraded: bool, buffered: Vec<u8>) -> io::Result<()> {
+        let token = Token(self.next_token.fetch_add(1, Ordering::Relaxed));
+        upstream.set_nonblocking(true)?;
+        let upstream = TcpStream::from_std(upstream);
+        let to_upstream = if upgraded { buffered } else { vec![] };
+        self.add(token, connection, |client| Command::Tunnel(token, Tunnel::new(client, upstream, upgraded, to_upstream)))
+    }
+
+    /// Send the command that adds connection. Sockets are polled by the hub,
+    /// other connections are read by a thread of their own, that passes on what it reads.
+    fn add<C: Connection>(&self, token: Token, connection: C, command: impl FnOnce(Stream) -> Command) -> io::Result<()> {
+        let connection = match connection.into_tcp_stream() {
+            Ok(stream) => {
+                stream.set_nonblocking(true)?;
+                self.send(command(Stream::Polled(TcpStream::from_std(stream))));
+                return Ok(());
+            },
+            Err(connection) => connection,
+        };
+        let mut reader = connection.try_clone()?;
+        self.send(command(Stream::Piped(Box::new(connection))));
+        let hub = self.clone();
+        thread::spawn(move || {
+            let mut buf = [0u8; 8192];
+            loop {
+                match reader.read(&mut buf) {
+                    Ok(0) => break,
+                    Ok(len) => hub.send(Command::Received(token, buf[..len].to_vec())),
+                    Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(_) => break,
+                }
+            }
+            hub.send(Command::Received(token, vec![]));
+        });
+        Ok(())
+    }
+
+    /// Send a text message to all clients.
+    pub(crate) fn broadcast(&self, msg: &[u8]) {
+        let mut frame = vec![];
+        let Ok(_) = write_message(&mut frame, Opcode::Text, msg) else { return };
+        self.send(Command::Send(frame));
+    }
+
+    /// Send a text message to all clients, and to each client that is added later, until another one is retained.
+    pub(crate) fn broadcast_retained(&self, msg: &[u8]) {
+        let mut frame = vec![];
+        let Ok(_) = write_message(&mut frame, Opcode::Text, msg) else { return };
+        self.send(Command::Retain(frame));
+    }
+
+    fn send(&self, command: Command) {
+        if self.commands.send(command).is_ok() {
+            let _ = self.waker.wake();
+        }
+    }
+}
+
+impl Drop for WebSocketHub {
+    fn drop(&mut self) {
+        // Drop the sender before waking the thread, so that it can tell if this was the last handle.
+        drop(std::mem::replace(&mut self.commands, mpsc::channel().0));
+        let _ = self.waker.wake();
+    }
+}
+
+fn run(mut poll: Poll, commands: Receiver<Command>, on_message: impl Fn(SocketAddr, Message)) {
+    let mut events = Events::with_capacity(128);
+    let mut clients: HashMap<Token, Client> = HashMap::new();
+    let mut tunnels: HashMap<Token, Tunnel> = HashMap::new();
+    let mut retained: Option<Vec<u8>> = None;
+    loop {
+        if let Err(err) = poll.poll(&mut events, None) {
+            if err.kind() == io::ErrorKind::Interrupted { continue };
+            eprintln!("WebSocket poll failed: {}", err);
+            return;
+        }
+        for event in events.iter() {
+            // Each event might let bytes through in either direction.
+            if let Some(tunnel) = tunnels.get_mut(&Token(event.token().0 & !UPSTREAM)) { tunnel.pump(); continue };
+            let Some(client) = clients.get_mut(&event.token()) else { continue };
+            if event.is_readable() { client.receive(&on_message) };
+            if event.is_writable() { client.flush() };
+        }
+        loop {
+            match commands.try_recv() {
+                Ok(Command::Add(token, stream, addr, buffered)) => {
+                    let client = Client::new(poll.registry(), token, stream, addr, buffered);
+                    let Ok(mut client) = client.map_err(|err| eprintln!("{}", err)) else { continue };
+                    if let Some(frame) = &retained { client.queue(frame) };
+                    client.process(&on_message);
+                    clients.insert(token, client);
+                },
+                Ok(Command::Tunnel(token, mut tunnel)) => {
+                    let Ok(_) = tunnel.register(poll.registry(), token).map_err(|err| eprintln!("{}", err)) else { continue };
+                    tunnel.pump();
+                    tunnels.insert(token, tunnel);
+                },
+                Ok(Command::Received(token, bytes)) => {
+                    if let Some(tunnel) = tunnels.get_mut(&token) { tunnel.receive(bytes); continue };
+                    let Some(client) = clients.get_mut(&token) else { continue };
+                    if bytes.is_empty() { client.closed = true };
+                    client.received.extend_from_slice(&bytes);
+                    client.process(&on_message);
+                },
+                Ok(Command::Send(frame)) => clients.values_mut().for_each(|client| client.queue(&frame)),
+                Ok(Command::Retain(frame)) => {
+                    clients.values_mut().for_each(|client| client.queue(&frame));
+                    retained = Some(frame);
+                },
+                Err(TryRecvError::Empty) => break,
+                // The server is gone, and so are the clients.
+                Err(TryRecvError::Disconnected) => return,
+            }
+        }
+        clients.retain(|_, client| {
+            if !client.closed { return true };
+            if let Stream::Polled(stream) = &mut client.stream {
+                let _ = poll.registry().deregister(stream);
+            }
+            false
+        });
+        tunnels.retain(|_, tunnel| {
+            if !tunnel.closed { return true };
+            tunnel.deregister(poll.registry());
+            false
+        });
+    }
+}
+
+struct Client {
+    stream: Stream,
+    addr: SocketAddr,
+    /// Bytes of frames that have not fully arrived yet
+    received: Vec<u8>,
+    /// Bytes that the socket could not take yet
+    queued: Vec<u8>,
+    assembler: MessageAssembler,
+    /// A close frame has been sent, so the connection is closed once it is flushed.
+    closing: bool,
+    closed: bool,
+}
+
+impl Client {
+    fn new(registry: &Registry, token: Token, mut stream: Stream, addr: SocketAddr, buffered: Vec<u8>) -> io::Result<Self> {
+        if let Stream::Polled(stream) = &mut stream {
+            registry.register(stream, token, Interest::READABLE | Interest::WRITABLE)?;
+        }
+        Ok(Client {
+            stream,
+            addr,
+            received: buffered,
+            queued: vec![],
+            assembler: MessageAssembler::new(),
+            closing: false,
+            closed: false,
+        })
+    }
+
+    /// Read everything the socket has, since mio only reports when more data arrives.
+    fn receive(&mut self, on_message: &impl Fn(SocketAddr, Message)) {
+        let Stream::Polled(stream) = &mut self.stream else { return };
+        let mut buf = [0u8; 8192];
+        loop {
+            match stream.read(&mut buf) {
+                Ok(0) => { self.closed = true; break },
+                Ok(len) => self.received.extend_from_slice(&buf[..len]),
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(_) => { self.closed = true; break },
+            }
+        }
+        self.process(on_message);
+    }
+
+    /// Handle all complete frames that have been received.
+    fn process(&mut self, on_message: &impl Fn(SocketAddr, Message)) {
+        while !self.closing && !self.closed {
+            let Some(len) = frame_len(&self.received) else { return };
+            if (self.received.len() as u64) < len && len <= MAX_FRAME_LEN { return };
+            let mut cursor = &self.received[..];
+            let frame = read_frame(&mut cursor);
+            let consumed = self.received.len() - cursor.len();
+            self.received.drain(..consumed);
+            if let Err(err) = frame.and_then(|frame| self.handle_frame(frame, on_message)) {
+                self.fail(err);
+            }
+        }
+    }
+
+    fn handle_frame(&mut self, frame: Frame, on_message: &impl Fn(SocketAddr, Message)) -> Result<()> {
+        if !frame.masked { return Err(WebSocketErrorKind::UnmaskedFrame.into()) };
+        match frame.opcode {
+            Opcode::Ping => self.queue_message(Opcode::Pong, &frame.payload),
+            Opcode::Pong => (),
+            Opcode::Close => {
+                // Echo the status code back to complete the closing handshake
+                self.close(frame.payload.get(0..2).unwrap_or_default());
+            },
+            _ => if let Some(message) = self.assembler.push(frame)? { on_message(self.addr, message) },
+        }
+        Ok(())
+    }
+
+    fn fail(&mut self, err: Error) {
+        match err {
+            Error::WebSocketError(err) => {
+                eprintln!("{}", err);
+                self.close(&err.close_code().to_be_bytes());
+            },
+            _ => self.closed = true,
+        }
+    }
+
+    /// Send a close frame. The connection is closed once it has been written.
+    fn close(&mut self, status_code: &[u8]) {
+        self.queue_message(Opcode::Close, status_code);
+        self.closing = true;
+        self.flush();
+    }
+
+    fn queue_message(&mut self, opcode: Opcode, payload: &[u8]) {
+        let mut frame = vec![];
+        let Ok(_) = write_message(&mut frame, opcode, payload) else { return };
+        self.queue(&frame);
+    }
+
+    fn queue(&mut self, frame: &[u8]) {
+        if self.closing || self.closed { return };
+        if self.queued.len() + frame.len() > MAX_QUEUED_LEN {
+            eprintln!("WebSocket client {} is not reading, closing the connection", self.addr);
+            self.closed = true;
+            return;
+        }
+        self.queued.extend_from_slice(frame);
+        self.flush();
+    }
+
+    /// Write as much of the queue as the socket takes. The rest is written when it becomes writable again.
+    fn flush(&mut self) {
+        while !self.queued.is_empty() && !self.closed {
+            match self.stream.write(&self.queued) {
+                Ok(0) => self.closed = true,
+                Ok(len) => { self.queued.drain(..len); },
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => return,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(_) => self.closed = true,
+            }
+        }
+        if self.closing { self.closed = true };
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        // Other handles of a piped connection keep it open, like the one its reading thread uses.
+        if let Stream::Piped(stream) = &self.stream { stream.shutdown() };
+    }
+}
+
+/// A proxied connection and its connection to upstream. Bytes are passed on as fast as the receiving side takes them,
+/// and reading from the sending side waits while too many are queued.
+struct Tunnel {
+    client: Stream,
+    upstream: TcpStream,
+    /// The connection was upgraded, so bytes from the client go upstream. Otherwise they are ignored,
+    /// since the connection is used up by the proxied request.
+    upgraded: bool,
+    /// Bytes from the client that upstream could not take yet
+    to_upstream: Vec<u8>,
+    /// Bytes from upstream that the client could not take yet
+    to_client: Vec<u8>,
+    /// The client has sent everything it will send.
+    client_ended: bool,
+    upstream_shut_down: bool,
+    /// Upstream is done and everything has been passed on, or either side failed.
+    closed: bool,
+}
+
+impl Tunnel {
+    fn new(client: Stream, upstream: TcpStream, upgraded: bool, to_upstream: Vec<u8>) -> Self {
+        Tunnel {
+            client,
+            upstream,
+            upgraded,
+            to_upstream,
+            to_client: vec![],
+            client_ended: false,
+            upstream_shut_down: false,
+            closed: false,
+        }
+    }
+
+    fn register(&mut self, registry: &Registry, token: Token) -> io::Result<()> {
+        if let Stream::Polled(client) = &mut self.client {
+            registry.register(client, token, Interest::READABLE | Interest::WRITABLE)?;
+        }
+        registry.register(&mut self.upstream, Token(token.0 | UPSTREAM), Interest::READABLE | Interest::WRITABLE)
+    }
+
+    fn deregister(&mut self, registry: &Registry) {
+        if let Stream::Polled(client) = &mut self.client {
+            let _ = registry.deregister(client);
+        }
+        let _ = registry.deregister(&mut self.upstream);
+    }
+
+    /// Bytes read from a piped client, empty once it is closed.
+    fn receive(&mut self, bytes: Vec<u8>) {
+        if bytes.is_empty() { self.client_ended = true };
+        if self.upgraded && !self.closed {
+            if self.to_upstream.len() + bytes.len() > MAX_QUEUED_LEN {
+                eprintln!("Proxy upstream is not reading, closing the connection");
+                self.closed = true;
+                return;
+            }
+            self.to_upstream.extend_from_slice(&bytes);
+        }
+        self.pump();
+    }
+
+    /// Pass on everything that the sockets allow, in both directions.
+    fn pump(&mut self) {
+        if self.closed { return };
+        if self.try_pump().is_err() { self.closed = true };
+    }
+
+    fn try_pump(&mut self) -> io::Result<()> {
+        // Bytes from a piped client arrive with receive instead.
+        if let (Stream::Polled(client), false) = (&mut self.client, self.client_ended) {
+            self.client_ended = match self.upgraded {
+                true => transfer(client, &mut self.upstream, &mut self.to_upstream)?,
+                false => transfer(client, &mut io::sink(), &mut vec![])?,
+            };
+        }
+        write_queued(&mut self.upstream, &mut self.to_upstream)?;
+        if self.upgraded && self.client_ended && self.to_upstream.is_empty() && !self.upstream_shut_down {
+            // Upstream might have more to send still, like its part of a closing handshake.
+            self.upstream.shutdown(Shutdown::Write)?;
+            self.upstream_shut_down = true;
+        }
+        if transfer(&mut self.upstream, &mut self.client, &mut self.to_client)? && self.to_client.is_empty() {
+            self.closed = true;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Tunnel {
+    fn drop(&mut self) {
+        if let Stream::Piped(stream) = &self.client { stream.shutdown() };
+    }
+}
+
+/// Pass on bytes from source to sink, through queue, until source has nothing more to read right now,
+/// or sink takes no more while the queue is full. Returns true at the end of source.
+fn transfer(source: &mut impl Read, sink: &mut impl Write, queue: &mut Vec<u8>) -> io::Result<bool> {
+    let mut buf = [0u8; 8192];
+    loop {
+        write_queued(sink, queue)?;
+        if queue.len() >= MAX_QUEUED_LEN { return Ok(false) };
+        match source.read(&mut buf) {
+            Ok(0) => return Ok(true),
+            Ok(len) => queue.extend_from_slice(&buf[..len]),
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Write as much of queue as sink takes.
+fn write_queued(sink: &mut impl Write, queue: &mut Vec<u8>) -> io::Result<()> {
+    while !queue.is_empty() {
+        match sink.write(queue) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(len) => { queue.drain(..len); },
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
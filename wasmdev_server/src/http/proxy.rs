@@ -0,0 +1,67 @@
+use std::io::{BufWriter, Write};
+use std::net::TcpStream;
+
+use super::error::*;
+use super::{Request, Header, StatusCode, make_http_response, write_response};
+
+/// Split an upstream url like "http://127.0.0.1:3001/base" into authority and base path.
+/// Only plain http is supported.
+pub fn parse_upstream(url: &str) -> Option<(&str, &str)> {
+    let rest = url.strip_prefix("http://")?;
+    let (authority, base_path) = match rest.find('/') {
+        Some(i) => rest.split_at(i),
+        None => (rest, ""),
+    };
+    if authority.is_empty() { return None };
+    Some((authority, base_path.trim_end_matches('/')))
+}
+
+/// True if prefix is path or one of its parent directories, like "/api" for "/api/users".
+pub fn is_path_prefix(prefix: &str, path: &str) -> bool {
+    let prefix = prefix.trim_end_matches('/');
+    match path.strip_prefix(prefix) {
+        Some(rest) => rest.is_empty() || rest.starts_with('/') || rest.starts_with('?'),
+        None => false,
+    }
+}
+
+/// Forward a request, including its already read body, to the upstream server.
+/// Returns the connection to upstream, which the response, or an upgraded connection, is passed on through.
+/// Answers 502 to the client if upstream can't be reached.
+pub fn forward_request<W: Write>(request: &Request, upstream: &str, writer: &mut BufWriter<W>) -> Result<TcpStream> {
+    let Some((authority, base_path)) = parse_upstream(upstream) else {
+        write_response(writer, &make_http_response(StatusCode(502), vec![], None))?;
+        return Err(Error::format_error(format!("Invalid proxy upstream '{upstream}', expected 'http://host:port'")));
+    };
+    let is_upgrade = request.headers().any(|h| matches!(h, Header::Upgrade(_)));
+    let upstream_stream = match TcpStream::connect(authority) {
+        Ok(stream) => stream,
+        Err(err) => {
+            write_response(writer, &make_http_response(StatusCode(502), vec![], None))?;
+            return Err(err.into());
+        },
+    };
+    let mut upstream_writer = BufWriter::new(&upstream_stream);
+    write!(upstream_writer, "{} {}{} {}\r\n", request.request_type, base_path, request.path, request.version)?;
+    for header in request.headers() {
+        match header {
+            Header::Host(_) => write!(upstream_writer, "{}\r\n", Header::host(authority))?,
+            // Let upstream close the connection after the response, so that the end of it is easy to find.
+            Header::Connection(_) if !is_upgrade => (),
+            // The body has already been read, so it is always sent with Content-Length.
+            Header::ContentLength(_) | Header::TransferEncoding(_) => (),
+            // The client has already been told to continue, and the body has been read.
+            Header::Expect(_) => (),
+            header => write!(upstream_writer, "{}\r\n", header)?,
+        }
+    }
+    if !is_upgrade { write!(upstream_writer, "{}\r\n", Header::connection("close"))? };
+    if request.content_length().is_some() || request.is_chunked() {
+        write!(upstream_writer, "{}\r\n", Header::ContentLength(request.body.len()))?;
+    }
+    write!(upstream_writer, "\r\n")?;
+    upstream_writer.write_all(&request.body)?;
+    upstream_writer.flush()?;
+    drop(upstream_writer);
+    Ok(upstream_stream)
+}
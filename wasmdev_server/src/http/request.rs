@@ -2,18 +2,23 @@ use std::fmt;
 use std::str::FromStr;
 use std::slice::Iter;
 
+use wasmdev_core::compress::Encoding;
+
 use super::{Version, Header, write_headers};
 use super::error::*;
 
 
-pub enum RequestType{ GET, PUT, POST, DELETE }
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RequestType{ GET, HEAD, PUT, POST, DELETE, OPTIONS }
 impl fmt::Display for RequestType {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             Self::GET => write!(f, "GET"),
+            Self::HEAD => write!(f, "HEAD"),
             Self::PUT => write!(f, "PUT"),
             Self::POST => write!(f, "POST"),
             Self::DELETE => write!(f, "DELETE"),
+            Self::OPTIONS => write!(f, "OPTIONS"),
         }
     }
 }
@@ -23,9 +28,11 @@ impl FromStr for RequestType{
     fn from_str(s: &str) -> Result<RequestType> {
         match s.to_ascii_uppercase().as_str() {
             "GET" => Ok(RequestType::GET),
+            "HEAD" => Ok(RequestType::HEAD),
             "PUT" => Ok(RequestType::PUT),
             "POST" => Ok(RequestType::POST),
             "DELETE" => Ok(RequestType::DELETE),
+            "OPTIONS" => Ok(RequestType::OPTIONS),
             _ => Err(HttpErrorKind::UnsupportedReqTypeError.into())
         }
     }
@@ -37,11 +44,68 @@ pub struct Request {
     pub path: String,
     pub version: Version,
     pub headers: Vec<Header>,
+    /// Empty until read with read_body
+    pub body: Vec<u8>,
 }
 impl Request {
-    pub fn headers(&self) -> Iter<Header> {
+    pub fn headers(&self) -> Iter<'_, Header> {
         self.headers.iter()
     }
+    /// True if the client wants to send more requests on this connection, see RFC 9112 section 9.3
+    pub fn keep_alive(&self) -> bool {
+        let has_option = |option: &str| self.headers().any(|h| matches!(h, Header::Connection(s) 
+            if s.split(',').any(|s| s.trim().eq_ignore_ascii_case(option))));
+        match self.version {
+            Version::V1_0 => has_option("keep-alive") && !has_option("close"),
+            Version::V1_1 => !has_option("close"),
+        }
+    }
+    /// True for a CORS preflight request, which asks if a cross-origin request is allowed before it is sent.
+    pub fn is_preflight(&self) -> bool {
+        matches!(self.request_type, RequestType::OPTIONS) && 
+        self.headers().any(|h| matches!(h, Header::AccessControlRequestMethod(_)))
+    }
+    pub fn content_length(&self) -> Option<usize> {
+        self.headers().find_map(|h| match h { Header::ContentLength(len) => Some(*len), _ => None })
+    }
+    /// True if the body is sent with chunked transfer encoding, which takes precedence over Content-Length.
+    pub fn is_chunked(&self) -> bool {
+        self.headers().any(|h| matches!(h, Header::TransferEncoding(s) if s.to_ascii_lowercase().contains("chunked")))
+    }
+    /// True if the client waits for 100 Continue before it sends the body, see RFC 9110 section 10.1.1
+    pub fn expects_continue(&self) -> bool {
+        matches!(self.version, Version::V1_1) &&
+        self.headers().any(|h| matches!(h, Header::Expect(s) if s.eq_ignore_ascii_case("100-continue")))
+    }
+    /// The encoding with the highest quality value in "Accept-Encoding", if any is supported.
+    /// Ties are broken by the order in Encoding::ALL.
+    pub fn preferred_encoding(&self) -> Option<Encoding> {
+        let accept_encoding = self.headers().find_map(|h| match h { Header::AcceptEncoding(s) => Some(s), _ => None })?;
+        // Parse "br;q=1.0, gzip;q=0.8, *;q=0.1" into [("br", 1.0), ("gzip", 0.8), ("*", 0.1)]
+        let codings: Vec<(&str, f32)> = accept_encoding.split(',')
+            .map(|coding| {
+                let mut params = coding.split(';');
+                let name = params.next().unwrap_or_default().trim();
+                let quality = params
+                    .find_map(|param| param.trim().strip_prefix("q=")?.trim().parse().ok())
+                    .unwrap_or(1.0);
+                (name, quality)
+            })
+            .collect();
+        let quality_of = |encoding: Encoding| codings.iter()
+            .find(|(name, _)| Encoding::from_name(name) == Some(encoding))
+            .or_else(|| codings.iter().find(|(name, _)| *name == "*"))
+            .map(|(_, quality)| *quality)
+            .unwrap_or(0.0);
+        Encoding::ALL.into_iter()
+            .map(|encoding| (encoding, quality_of(encoding)))
+            .filter(|(_, quality)| *quality > 0.0)
+            .fold(None, |best: Option<(Encoding, f32)>, (encoding, quality)| match best {
+                Some((_, best_quality)) if best_quality >= quality => best,
+                _ => Some((encoding, quality)),
+            })
+            .map(|(encoding, _)| encoding)
+    }
 }
 
 impl fmt::Display for Request {
@@ -55,21 +119,69 @@ impl fmt::Display for Request {
 impl FromStr for Request{
     type Err = Error;
     #[inline]
-    fn from_str(s: &str) -> Result<Request> {(|| {
+    fn from_str(s: &str) -> Result<Request> {
         let mut lines = s.split("\r\n");
-        let mut words = lines.next()?.split(" ");
-        let request_type: RequestType = words.next()?.parse().ok()?;
-        let path                      = words.next()?.to_string();
-        let version: Version          = words.next()?.parse().ok()?;
-        let headers: Vec<Header> = lines
-            // FIXME: If header fails to parse here, maybe the whole request should fail.
-            .filter_map(|s| s.parse::<Header>().ok())
-            .collect();
-        Some(Request {
+        let request_line = lines.next().unwrap_or_default();
+        let [request_type, path, version] = request_line.split(' ').collect::<Vec<_>>()[..] else {
+            return Err(Error::format_error(format!("invalid request line '{request_line}'")));
+        };
+        if path.is_empty() { return Err(Error::format_error(format!("missing path in request line '{request_line}'"))) };
+        let request_type: RequestType = request_type.parse()?;
+        let version: Version          = version.parse()?;
+        let headers: Vec<Header>      = lines.map(str::parse).collect::<Result<_>>()?;
+        Ok(Request {
             request_type,
-            path,
+            path: path.to_string(),
             version,
             headers,
+            body: vec![],
         })
-    })().ok_or(Error::format_error(format!("unable to parse request: '{s}'")))}
-}
\ No newline at end of file
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn preferred_encoding(accept_encoding: &str) -> Option<Encoding> {
+        let req: Request = format!("GET / HTTP/1.1\r\nAccept-Encoding: {accept_encoding}").parse().unwrap();
+        req.preferred_encoding()
+    }
+
+    #[test]
+    fn highest_quality_wins() {
+        assert_eq!(preferred_encoding("gzip"), Some(Encoding::Gzip));
+        assert_eq!(preferred_encoding("gzip, deflate, br"), Some(Encoding::Brotli));
+        assert_eq!(preferred_encoding("gzip;q=1.0, br;q=0.5"), Some(Encoding::Gzip));
+        assert_eq!(preferred_encoding("BR;q=0.2, x-gzip;q=0.1"), Some(Encoding::Brotli));
+        assert_eq!(preferred_encoding("deflate"), None);
+    }
+
+    #[test]
+    fn zero_quality_is_not_acceptable() {
+        assert_eq!(preferred_encoding("br;q=0, gzip"), Some(Encoding::Gzip));
+        assert_eq!(preferred_encoding("br;q=0, gzip;q=0.0"), None);
+    }
+
+    #[test]
+    fn wildcard() {
+        assert_eq!(preferred_encoding("*"), Some(Encoding::Brotli));
+        assert_eq!(preferred_encoding("br;q=0, *"), Some(Encoding::Gzip));
+        assert_eq!(preferred_encoding("gzip;q=0.5, *;q=0.8"), Some(Encoding::Brotli));
+        assert_eq!(preferred_encoding("*;q=0"), None);
+    }
+
+    #[test]
+    fn identity() {
+        // Refusing identity doesn't make anything else acceptable, the response is then sent unencoded.
+        assert_eq!(preferred_encoding("identity;q=0"), None);
+        assert_eq!(preferred_encoding("identity;q=0, gzip"), Some(Encoding::Gzip));
+        assert_eq!(preferred_encoding("identity"), None);
+    }
+
+    #[test]
+    fn no_accept_encoding() {
+        let req: Request = "GET / HTTP/1.1\r\nHost: localhost".parse().unwrap();
+        assert_eq!(req.preferred_encoding(), None);
+    }
+}
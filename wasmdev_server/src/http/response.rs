@@ -1,13 +1,31 @@
 use std::fmt;
 
-use super::{Version, StatusCode, Header, write_headers};
+use super::{Body, Version, StatusCode, Header, write_headers};
 
 
 pub struct Response<'a> {
     pub version: Version,
     pub status_code: StatusCode,
     pub headers: Vec<Header>,
-    pub body: Option<&'a Vec<u8>>,
+    pub body: Option<Body<'a>>,
+}
+impl Response<'static> {
+    /// An empty response, for request handlers to build upon.
+    pub fn new(status_code: StatusCode) -> Self {
+        Response { version: Version::V1_1, status_code, headers: vec![], body: None }
+    }
+    pub fn with_header(mut self, header: Header) -> Self {
+        self.headers.push(header);
+        self
+    }
+    /// Set the body, and the Content-Length header to match it.
+    pub fn with_body(mut self, body: impl Into<Body<'static>>) -> Self {
+        let body = body.into();
+        self.headers.retain(|h| !matches!(h, Header::ContentLength(_)));
+        self.headers.push(Header::ContentLength(body.len()));
+        self.body = Some(body);
+        self
+    }
 }
 impl <'a> fmt::Display for Response<'a> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -2,20 +2,52 @@ use std::collections::HashMap;
 use std::marker::PhantomData;
 use std::net::{TcpListener, TcpStream, SocketAddr};
 use std::io::{self, BufWriter};
-use std::io::{BufRead, BufReader, Write};
+use std::io::{BufRead, BufReader, Read, Write};
 use std::fs;
-use std::path::Path;
-use std::sync::{Arc, RwLock};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex, OnceLock};
 use std::thread;
+use std::time::{Duration, Instant, UNIX_EPOCH};
 
-use crate::utils::{defer, hash_bytes};
-use crate::http::{Header, StatusCode};
+use crate::utils::hash_bytes;
+use crate::http::{Body, ByteRange, Connection, Cors, Header, HttpErrorKind, ParseErrorKind, Request, RequestType, Response, Version, StatusCode, Error, Result};
 use crate::http::helpers::*;
+use crate::http::proxy::*;
+use wasmdev_core::compress::Encoding;
+use arc_swap::ArcSwap;
+use crate::http::hub::WebSocketHub;
+use crate::http::websocket::Message;
 
-#[derive(PartialEq)]
 pub struct Endpoint{
     headers: Vec<Header>,
     response_action: Option<ResponseAction>,
+    /// Headers and body of a lazily loaded file, read on the first request
+    loaded_file: OnceLock<(Vec<Header>, Vec<u8>)>,
+    /// Compressed variants of the body, created on demand
+    encoded_bodies: EncodedBodies,
+}
+
+impl Endpoint {
+    fn new(headers: Vec<Header>, response_action: Option<ResponseAction>) -> Self {
+        Endpoint { headers, response_action, loaded_file: OnceLock::new(), encoded_bodies: EncodedBodies::default() }
+    }
+}
+
+/// Endpoints never change once they are built, so a compressed body stays valid for as long as the endpoint exists.
+#[derive(Default)]
+struct EncodedBodies {
+    brotli: OnceLock<Vec<u8>>,
+    gzip: OnceLock<Vec<u8>>,
+}
+
+impl EncodedBodies {
+    fn get(&self, encoding: Encoding) -> &OnceLock<Vec<u8>> {
+        match encoding {
+            Encoding::Brotli => &self.brotli,
+            Encoding::Gzip => &self.gzip,
+        }
+    }
 }
 
 pub enum EndpointHasResponse {}
@@ -31,17 +63,39 @@ pub trait EndpointBuilderNoResponse<'a> : EndpointBuilderHasResponse {
     fn internal_redirect(self, path: &'a str) -> EndpointBuilder<'a, EndpointHasResponse>;
     fn set_response_body(self, body: Vec<u8>) -> EndpointBuilder<'a, EndpointHasResponse>;
     fn lazy_load(self, path: &'a str)         -> EndpointBuilder<'a, EndpointHasResponse>;
+    /// Forward requests to this path, and all paths below it, to an upstream server like "http://127.0.0.1:3001"
+    fn proxy(self, upstream: &'a str)         -> EndpointBuilder<'a, EndpointHasResponse>;
+    /// Respond with the response returned by handler
+    fn handler(self, handler: impl Fn(&Request) -> Response<'static> + Send + Sync + 'static) -> EndpointBuilder<'a, EndpointHasResponse>;
 }
 
-#[derive(PartialEq)]
+pub type RequestHandler = Arc<dyn Fn(&Request) -> Response<'static> + Send + Sync>;
+
+#[derive(Clone)]
 pub enum ResponseAction {
     Content(Vec<u8>),
     InternalRedirect(String),
     LazyLoad(String),
+    Proxy(String),
+    Handler(RequestHandler),
+}
+
+impl PartialEq for ResponseAction {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::Content(a), Self::Content(b)) => a == b,
+            (Self::InternalRedirect(a), Self::InternalRedirect(b)) => a == b,
+            (Self::LazyLoad(a), Self::LazyLoad(b)) => a == b,
+            (Self::Proxy(a), Self::Proxy(b)) => a == b,
+            (Self::Handler(a), Self::Handler(b)) => Arc::ptr_eq(a, b),
+            _ => false,
+        }
+    }
 }
 
 pub struct EndpointBuilder<'a, T> {
     server_config: &'a mut ServerConfig,
+    method: RequestType,
     path: &'a str,
     endpoint: Endpoint,
     _marker: PhantomData<T> 
@@ -75,11 +129,23 @@ impl <'a, T> EndpointBuilderHasResponse for EndpointBuilder<'a, T> {
                 endpoint.headers.push(Header::ContentLength(size));
             }
         }
+        if !endpoint.headers.iter().any(|h| matches!(h, Header::ETag(_))) {
+            if let Some(ResponseAction::Content(body)) = &endpoint.response_action {
+                endpoint.headers.push(Header::ETag(make_etag(body)));
+            }
+        }
+        // Make browsers revalidate cached responses, so that a reload always gets the latest version.
+        if !endpoint.headers.iter().any(|h| matches!(h, Header::CacheControl(_))) {
+            endpoint.headers.push(Header::cache_control("no-cache"));
+        }
         let endpoint_hash = match &endpoint.response_action {
             Some(ResponseAction::Content(body)) => Some(hash_bytes(body)),
             _ => None,
         };
-        let Some(old_endpoint) = self.server_config.endpoints.insert(self.path.into(), endpoint) else {
+        let methods = self.server_config.endpoints.entry(self.path.into()).or_default();
+        let old_endpoint = methods.insert(self.method, Arc::new(endpoint));
+        self.server_config.update_proxy(self.path);
+        let Some(old_endpoint) = old_endpoint else {
             return true;
         };
         let old_endpoint_hash = match &old_endpoint.response_action {
@@ -98,10 +164,8 @@ impl <'a, T> EndpointBuilderHasResponse for EndpointBuilder<'a, T> {
             headers.push(header);
         }
         Self {
-            endpoint: Endpoint { 
-                headers: headers, 
-                response_action: self.endpoint.response_action 
-            },
+            endpoint: Endpoint::new(headers, self.endpoint.response_action),
+            method: self.method,
             path: self.path,
             server_config: self.server_config,
             _marker: self._marker,
@@ -113,193 +177,630 @@ impl <'a> EndpointBuilderNoResponse<'a> for EndpointBuilder<'a, EndpointNoRespon
     fn internal_redirect(self, path: &'a str) -> EndpointBuilder<'a, EndpointHasResponse> {
         EndpointBuilder { 
             server_config: self.server_config, 
+            method: self.method,
             path: self.path,
-            endpoint: Endpoint { 
-                headers: self.endpoint.headers, 
-                response_action: Some(ResponseAction::InternalRedirect(path.to_owned())),
-            },
+            endpoint: Endpoint::new(self.endpoint.headers, Some(ResponseAction::InternalRedirect(path.to_owned()))),
             _marker: Default::default()
         }
     }
     fn set_response_body(self, body: Vec<u8>) -> EndpointBuilder<'a, EndpointHasResponse> {
         EndpointBuilder { 
             server_config: self.server_config,
+            method: self.method,
             path: self.path,
-            endpoint: Endpoint { 
-                headers: self.endpoint.headers, 
-                response_action: Some(ResponseAction::Content(body)),
-            },
+            endpoint: Endpoint::new(self.endpoint.headers, Some(ResponseAction::Content(body))),
             _marker: Default::default()
         }
     }
     fn lazy_load(self, path: &'a str) -> EndpointBuilder<'a, EndpointHasResponse> {
         EndpointBuilder { 
             server_config: self.server_config,
+            method: self.method,
             path: self.path,
-            endpoint: Endpoint { 
-                headers: self.endpoint.headers, 
-                response_action: Some(ResponseAction::LazyLoad(path.to_owned())),
-            },
+            endpoint: Endpoint::new(self.endpoint.headers, Some(ResponseAction::LazyLoad(path.to_owned()))),
+            _marker: Default::default()
+        }
+    }
+    fn proxy(self, upstream: &'a str) -> EndpointBuilder<'a, EndpointHasResponse> {
+        EndpointBuilder { 
+            server_config: self.server_config,
+            method: self.method,
+            path: self.path,
+            endpoint: Endpoint::new(self.endpoint.headers, Some(ResponseAction::Proxy(upstream.to_owned()))),
+            _marker: Default::default()
+        }
+    }
+    fn handler(self, handler: impl Fn(&Request) -> Response<'static> + Send + Sync + 'static) -> EndpointBuilder<'a, EndpointHasResponse> {
+        EndpointBuilder { 
+            server_config: self.server_config,
+            method: self.method,
+            path: self.path,
+            endpoint: Endpoint::new(self.endpoint.headers, Some(ResponseAction::Handler(Arc::new(handler)))),
             _marker: Default::default()
         }
     }
 }
 
+pub type MessageHandler = Arc<dyn Fn(SocketAddr, Message) + Send + Sync>;
+
 // This struct configures how the server should respond to requests
+#[derive(Clone)]
 pub struct ServerConfig{
-    endpoints: HashMap<String, Endpoint>,
+    endpoints: HashMap<String, HashMap<RequestType, Arc<Endpoint>>>,
+    /// Path prefix and upstream of each proxy endpoint, longest prefix first, so that requests don't scan all endpoints.
+    proxies: Vec<(String, String)>,
+    message_handler: Option<MessageHandler>,
+    compression: bool,
+    precompressed_dir: Option<PathBuf>,
+    navigation_fallback: Option<ResponseAction>,
+    cors: Option<Cors>,
+    max_body_len: usize,
+    idle_timeout: Duration,
+    header_timeout: Duration,
+    stream_threshold: u64,
+    worker_count: usize,
+}
+
+/// Files larger than this are streamed from disk instead of being kept in memory, by default.
+pub const DEFAULT_STREAM_THRESHOLD: u64 = 1024 * 1024;
+
+/// How long a connection is kept open while waiting for the next request, by default.
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long to wait for more data of a request that has started to arrive, by default.
+pub const DEFAULT_HEADER_TIMEOUT: Duration = Duration::from_secs(10);
+/// How often a worker that waits for the next request on a connection checks if other connections are waiting for it.
+const IDLE_POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// Number of threads that serve http connections, by default. WebSocket connections share one more thread.
+pub const DEFAULT_WORKER_COUNT: usize = 16;
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl ServerConfig {
     pub fn new() -> ServerConfig { 
         ServerConfig {
-            endpoints: HashMap::from([])
+            endpoints: HashMap::from([]),
+            proxies: vec![],
+            message_handler: None,
+            compression: false,
+            precompressed_dir: None,
+            navigation_fallback: None,
+            cors: None,
+            max_body_len: DEFAULT_MAX_BODY_LEN,
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            header_timeout: DEFAULT_HEADER_TIMEOUT,
+            stream_threshold: DEFAULT_STREAM_THRESHOLD,
+            worker_count: DEFAULT_WORKER_COUNT,
+        }
+    }
+
+    /// Remove the endpoint at path. Returns true if there was an endpoint to remove.
+    pub fn remove_endpoint(&mut self, path: &str) -> bool {
+        let removed = self.endpoints.remove(path).is_some();
+        self.update_proxy(path);
+        removed
+    }
+
+    /// Remove all endpoints below directory path. Returns the paths of removed endpoints.
+    pub fn remove_endpoints_in_dir(&mut self, dir_path: &str) -> Vec<String> {
+        let dir_path = format!("{}/", dir_path.trim_end_matches('/'));
+        let paths: Vec<String> = self.endpoints.keys()
+            .filter(|path| path.starts_with(&dir_path))
+            .cloned()
+            .collect();
+        for path in &paths {
+            self.endpoints.remove(path);
+            self.update_proxy(path);
+        }
+        paths
+    }
+
+    /// Register a callback for text and binary messages sent by websocket clients.
+    pub fn on_websocket_message(&mut self, handler: impl Fn(SocketAddr, Message) + Send + Sync + 'static) {
+        self.message_handler = Some(Arc::new(handler));
+    }
+
+    /// Compress text and wasm responses with gzip or brotli, if the client accepts it.
+    pub fn set_compression(&mut self, enabled: bool) {
+        self.compression = enabled;
+    }
+
+    /// Serve ".gz" and ".br" siblings from dir instead of compressing on the fly, when compression is enabled.
+    /// A sibling is only used if the uncompressed file in dir has the same content as the endpoint.
+    pub fn serve_precompressed(&mut self, dir: impl Into<PathBuf>) {
+        self.precompressed_dir = Some(dir.into());
+    }
+
+    /// Internally redirect navigations to paths without an endpoint, like "/settings/profile", to path.
+    /// Used by single page apps with client side routing. None disables the fallback.
+    pub fn set_navigation_fallback(&mut self, path: Option<&str>) {
+        self.navigation_fallback = path.map(|path| ResponseAction::InternalRedirect(path.to_owned()));
+    }
+
+    /// Allow apps on other origins to read responses. None disables CORS, which only allows the same origin.
+    /// Responses from proxied backends are not affected.
+    pub fn set_cors(&mut self, cors: Option<Cors>) {
+        self.cors = cors;
+    }
+
+    /// Largest request body to read. Requests with larger bodies get 413 Content Too Large.
+    pub fn set_max_body_len(&mut self, max_len: usize) {
+        self.max_body_len = max_len;
+    }
+
+    /// Stream lazily loaded files larger than len bytes from disk on every request, instead of keeping them in memory.
+    /// Streamed files are not compressed.
+    pub fn set_stream_threshold(&mut self, len: u64) {
+        self.stream_threshold = len;
+    }
+
+    /// Close connections that have not started a new request within timeout. Must not be zero.
+    pub fn set_idle_timeout(&mut self, timeout: Duration) {
+        self.idle_timeout = timeout;
+    }
+
+    /// Respond with 408 Request Timeout and close connections whose request head has not fully arrived within timeout
+    /// of its first byte. Also the longest pause while a body is read. Must not be zero.
+    pub fn set_header_timeout(&mut self, timeout: Duration) {
+        self.header_timeout = timeout;
+    }
+
+    /// Serve http connections with count threads. Takes effect the next time the server starts to listen.
+    /// Proxied and upgraded connections are passed on by the WebSocket hub, so they don't keep a worker.
+    pub fn set_worker_count(&mut self, count: usize) {
+        self.worker_count = count;
+    }
+
+    /// Upstream of the proxy endpoint with the longest path that is a prefix of path.
+    /// Other endpoints that match path exactly take precedence.
+    pub fn find_proxy(&self, path: &str) -> Option<&str> {
+        let path = path.split('?').next().unwrap_or_default();
+        let is_proxy = |endpoint: &Arc<Endpoint>| matches!(endpoint.response_action, Some(ResponseAction::Proxy(_)));
+        if let Some(methods) = self.endpoints.get(path) {
+            if !methods.values().all(is_proxy) { return None };
+        }
+        self.proxies.iter()
+            .find(|(prefix, _)| is_path_prefix(prefix, path))
+            .map(|(_, upstream)| upstream.as_str())
+    }
+
+    /// Keep proxies in sync with the endpoint at path, after it has been added, replaced or removed.
+    fn update_proxy(&mut self, path: &str) {
+        self.proxies.retain(|(prefix, _)| prefix != path);
+        let Some(upstream) = self.endpoints.get(path)
+            .and_then(|methods| methods.values().find_map(|endpoint| match &endpoint.response_action {
+                Some(ResponseAction::Proxy(upstream)) => Some(upstream.clone()),
+                _ => None,
+            })) else { return };
+        let index = self.proxies.partition_point(|(prefix, _)| prefix.len() >= path.len());
+        self.proxies.insert(index, (path.to_owned(), upstream));
+    }
+
+    /// Follow internal redirects and the navigation fallback to the endpoint that responds to a request.
+    fn resolve<'a>(&'a self, request: &'a Request) -> Resolved<'a> {
+        // Ignore query parameters
+        let mut path = request.path.split('?').next().unwrap_or_default();
+        let mut is_fallback = false;
+        loop {
+            let Some(methods) = self.endpoints.get(path) else {
+                // Client side routes are not endpoints, so let the app handle them.
+                match &self.navigation_fallback {
+                    Some(ResponseAction::InternalRedirect(fallback_path)) if !is_fallback && is_navigation(request) => {
+                        path = fallback_path;
+                        is_fallback = true;
+                        continue;
+                    },
+                    _ => return Resolved::NotFound,
+                }
+            };
+            // HEAD is answered like GET, without the body, unless it has an endpoint of its own.
+            let method = match request.request_type {
+                RequestType::HEAD if !methods.contains_key(&RequestType::HEAD) => RequestType::GET,
+                method => method,
+            };
+            let Some(endpoint) = methods.get(&method) else {
+                let mut allowed: Vec<RequestType> = methods.keys().copied().collect();
+                if methods.contains_key(&RequestType::GET) && !methods.contains_key(&RequestType::HEAD) { 
+                    allowed.push(RequestType::HEAD);
+                }
+                if !methods.contains_key(&RequestType::OPTIONS) { allowed.push(RequestType::OPTIONS) };
+                allowed.sort_by_key(|method| method.to_string());
+                return Resolved::MethodNotAllowed(allowed);
+            };
+            match &endpoint.response_action {
+                Some(ResponseAction::InternalRedirect(redirect_path)) => { path = redirect_path; },
+                Some(_) => return Resolved::Found(path, endpoint),
+                None => return Resolved::NotFound,
+            }
         }
     }
 
-    pub fn on_get_request<'a>(&'a mut self, path: &'a str) -> EndpointBuilder<EndpointNoResponse> {
+    #[inline]
+    pub fn on_get_request<'a>(&'a mut self, path: &'a str) -> EndpointBuilder<'a, EndpointNoResponse> {
+        self.on_request(RequestType::GET, path)
+    }
+
+    pub fn on_request<'a>(&'a mut self, method: RequestType, path: &'a str) -> EndpointBuilder<'a, EndpointNoResponse> {
         EndpointBuilder { 
             server_config: self, 
+            method,
             path,
-            endpoint: Endpoint { 
-                headers: vec![], 
-                response_action: None 
-            },
+            endpoint: Endpoint::new(vec![], None),
             _marker: Default::default()
         }
     }
 }
 
-#[derive(Clone)]
-pub struct Client {
-    writer: Arc<RwLock<BufWriter<TcpStream>>>,
-    addr: SocketAddr,
+enum Resolved<'a> {
+    Found(&'a str, &'a Endpoint),
+    MethodNotAllowed(Vec<RequestType>),
+    NotFound,
+}
+
+/// The latest configuration, as an immutable snapshot. Requests keep using the snapshot they started with,
+/// while configure builds the next one from a copy. Endpoints are shared between snapshots, so copies are cheap.
+#[derive(Default)]
+struct ConfigSnapshots {
+    /// Swapped atomically, so loading a snapshot never waits for configure, and configure never waits for requests.
+    current: ArcSwap<ServerConfig>,
+    /// Makes configure calls wait for each other, so that no change is lost.
+    writer: Mutex<()>,
+}
+
+impl ConfigSnapshots {
+    fn load(&self) -> Arc<ServerConfig> {
+        self.current.load_full()
+    }
+    fn update<R>(&self, f: impl FnOnce(&mut ServerConfig) -> R) -> R {
+        let _writer = self.writer.lock().unwrap();
+        let mut config = ServerConfig::clone(&self.load());
+        let result = f(&mut config);
+        self.current.store(Arc::new(config));
+        result
+    }
 }
 
 #[derive(Clone)]
 pub struct Server {
-    config: Arc<RwLock<ServerConfig>>,
-    clients: Arc<RwLock<Vec<Client>>>,
+    config: Arc<ConfigSnapshots>,
+    /// Started by the first call to listen
+    websocket_hub: Arc<OnceLock<WebSocketHub>>,
+    /// Accepted connections that are waiting for a worker
+    waiting: Arc<AtomicUsize>,
+}
+
+impl Default for Server {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Server{
     pub fn new() -> Self {
         Server {
-            config: Arc::new(RwLock::new(ServerConfig::new())),
-            clients: Arc::new(RwLock::new(vec![])),
+            config: Arc::new(ConfigSnapshots::default()),
+            websocket_hub: Arc::new(OnceLock::new()),
+            waiting: Arc::new(AtomicUsize::new(0)),
         }
     }
+    /// Change the configuration. Requests that are already being handled keep the configuration they started with.
     #[inline]
     pub fn configure<R>(&self, f: impl FnOnce(&mut ServerConfig) -> R) -> R {
-        f(&mut self.config.write().unwrap())
+        self.config.update(f)
     }
+    /// Send a text message to all WebSocket clients.
     pub fn broadcast(&self, msg: &[u8]) {
-        for client in self.clients.read().unwrap().iter() {
-            let mut writer = client.writer.write().unwrap();
-            let Ok(_) = writer.write_all(&[0x81, msg.len() as u8]).map_err(|err| eprintln!("{}", err)) else { continue };
-            let Ok(_) = writer.write_all(msg)                     .map_err(|err| eprintln!("{}", err)) else { continue };
-            let Ok(_) = writer.flush()                            .map_err(|err| eprintln!("{}", err)) else { continue };
+        if let Some(websocket_hub) = self.websocket_hub.get() {
+            websocket_hub.broadcast(msg);
+        }
+    }
+    /// Send a text message to all WebSocket clients, and to each client that connects later,
+    /// until another message is retained. Used for state that new clients need, like the result of the last build.
+    pub fn broadcast_retained(&self, msg: &[u8]) {
+        match self.websocket_hub() {
+            Ok(websocket_hub) => websocket_hub.broadcast_retained(msg),
+            Err(err) => eprintln!("{}", err),
         }
     }
+    /// Accept connections and serve them with a pool of worker threads, see ServerConfig::set_worker_count.
+    /// When all workers are busy, accepting waits, and new connections queue up in the listen backlog of the OS.
     pub fn listen(&self, listener: TcpListener) -> io::Result<()> {
+        let worker_count = self.config.load().worker_count.max(1);
+        let (sender, receiver) = mpsc::sync_channel::<TcpStream>(worker_count);
+        let receiver = Arc::new(Mutex::new(receiver));
+        for _ in 0..worker_count {
+            let server = self.clone();
+            let receiver = receiver.clone();
+            thread::spawn(move || loop {
+                // Workers stop once the listener is gone and the queue is empty.
+                let Ok(stream) = receiver.lock().unwrap().recv() else { return };
+                server.waiting.fetch_sub(1, Ordering::Relaxed);
+                server.handle_connection(stream);
+            });
+        }
         for stream in listener.incoming() {
             let stream = stream?;
-            let peer_addr = stream.peer_addr()?;
-            let config = self.config.clone();
-            let clients = self.clients.clone();
-            // FIXME: Decouple the server logic from the tcp-stream dependency
-            // Any struct with Read and Write capability should be enough.
-            let mut reader = BufReader::new(stream.try_clone()?);
-            let mut writer = BufWriter::new(stream);
-
-            // Each connection uses its own thread. Simple but does not scale. Fine for dev server.
-            thread::spawn(move || {
-                // defer! { eprintln!("Closed connection {peer_addr}") };
-                // eprintln!("Got Connection {}", peer_addr);
-                let mut upgrade_connection = false;
-                loop {
-                    let Ok(req) = parse_request(&mut reader) else { return };
-                    // If we have a lazy response, we need to store it at this scope-level
-                    // in order to cache it after response has been sent.
-                    let mut lazy_response = None;
-                    let send_ok = if is_valid_websocket(&req) { 
-                        upgrade_connection = true;
-                        let resp = make_websocket_accept_response(&req);
-                        let Ok(resp) = resp.map_err(|err| eprintln!("{}", err)) else { return };
-                        write_response(&mut writer, &resp)
-                    } else {
-                        // Ignore query parameters
-                        let mut path = req.path.split("?").next().unwrap();
-                        // eprintln!("Got req: {path} from {peer_addr}");
-                        let config = config.read().unwrap();
-                        let headers_and_action = loop {
-                            let Some(endpoint) = config.endpoints.get(path) else { break None };
-                            let Some(response_action) = &endpoint.response_action else { break None };
-                            match response_action {
-                                ResponseAction::InternalRedirect(redirect_path) => { path = &redirect_path; },
-                                ResponseAction::LazyLoad(file_path) => {
-                                    let Ok(body) = fs::read(&file_path) else { break None };
-                                    let mut headers = endpoint.headers.clone();
-                                    headers.push(Header::ContentLength(body.len()));
-                                    lazy_response = Some((path.to_string(), headers, ResponseAction::Content(body)));
-                                    let Some((_, headers, response_action)) = &lazy_response else { break None };
-                                    break Some((headers, response_action));
-                                },
-                                _ => {
-                                    break Some((&endpoint.headers, response_action));
-                                },
-                            }
-                        };
-                        let resp = {
-                            match headers_and_action {
-                                Some((headers, ResponseAction::Content(body))) => 
-                                    make_http_response(StatusCode(200), headers.clone(), Some(&body)),
-                                _ => 
-                                    make_http_response(StatusCode(404), vec![], None),
-                                
-                            }
-                        };
-                        write_response(&mut writer, &resp)
-                    };
-                    let Ok(_) = send_ok.map_err(|err| eprintln!("{}", err)) else { continue };
-                    // eprintln!("Sent HTTP response to {peer_addr}");
-                    if let Some((path, headers, ResponseAction::Content(body))) = lazy_response {
-                        config.write().unwrap()
-                            .on_get_request(&path)
-                            .add_response_headers(headers)
-                            .set_response_body(body)
-                            .build();
-                    }
-                    if upgrade_connection { break };
+            self.waiting.fetch_add(1, Ordering::Relaxed);
+            let Ok(_) = sender.send(stream) else { break };
+        }
+        Ok(())
+    }
+    fn websocket_hub(&self) -> io::Result<WebSocketHub> {
+        if let Some(websocket_hub) = self.websocket_hub.get() { return Ok(websocket_hub.clone()) };
+        let config = self.config.clone();
+        let websocket_hub = WebSocketHub::start(move |addr, message| {
+            let message_handler = config.load().message_handler.clone();
+            if let Some(message_handler) = message_handler {
+                message_handler(addr, message);
+            }
+        })?;
+        // Another listen call might have started one first, then that one is used and this one stops.
+        Ok(self.websocket_hub.get_or_init(|| websocket_hub).clone())
+    }
+    /// Serve requests on a connection until either side closes it, see RFC 9112 section 9.
+    /// Blocks the calling thread. Upgraded WebSocket connections, and proxied connections once their request has been
+    /// forwarded, are handed over to the WebSocket hub, which serves them in the background.
+    pub fn handle_connection<C: Connection>(&self, connection: C) {
+        let config = &self.config;
+        let handles = connection.peer_addr().and_then(|addr| Ok((addr, connection.try_clone()?)));
+        let Ok((peer_addr, reader_connection)) = handles.map_err(|err| eprintln!("{}", err)) else { return };
+        let mut reader = BufReader::new(DeadlineReader { connection: reader_connection, deadline: None });
+        let mut writer = BufWriter::new(connection);
+        let mut is_first_request = true;
+        loop {
+            let (idle_timeout, header_timeout, max_body_len) = {
+                let config = config.load();
+                (config.idle_timeout, config.header_timeout, config.max_body_len)
+            };
+            // Wait for the next request, but not forever.
+            // Idle connections also make room for new ones when all workers are busy.
+            let idle_since = Instant::now();
+            let _ = writer.get_ref().set_read_timeout(Some(idle_timeout.min(IDLE_POLL_INTERVAL)));
+            loop {
+                match reader.fill_buf() {
+                    Ok([]) => return,
+                    Ok(_) => break,
+                    Err(err) if matches!(err.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {
+                        if idle_since.elapsed() >= idle_timeout { return };
+                        if !is_first_request && self.waiting.load(Ordering::Relaxed) > 0 { return };
+                    },
+                    Err(_) => return,
                 }
-                defer! { 
-                    clients.write().unwrap().retain(|client| client.addr != peer_addr);
-                    // eprintln!("Closed WebSocket Connection {peer_addr}")
-                };
-                clients.write().unwrap().push(
-                    Client { writer: Arc::new(RwLock::new(writer)), addr: peer_addr }
-                );
-                // eprintln!("Got WebSocket Connection {}", peer_addr);
-                loop {
-                    let Ok(buffer) = reader.fill_buf().map_err(|err| eprintln!("{}", err)) else { return };
-                    let length = buffer.len();
-                    if length == 0 { break };
-                    
-                    // work with buffer
-                    // eprintln!("Received websocket message from {peer_addr}: {buffer:?}");
-                    
-                    // ensure the bytes we worked with aren't returned again later
-                    reader.consume(length);
-
-                    // // Reply with static "reload" message
-                    // let ws_message: [u8; 8] = [0x81, 0x06, b'r', b'e', b'l', b'o', b'a', b'd'];
-                    // eprintln!("Sending websocket message to {peer_addr}");
-                    // let Ok(_) = writer.write_all(&ws_message).map_err(|err| eprintln!("{}", err)) else { return };
-                    // let Ok(_) = writer.flush().map_err(|err| eprintln!("{}", err)) else { return };
-                    // // break;
-                };
+            }
+            is_first_request = false;
+            // The whole head must arrive within the header timeout, counted from its first byte.
+            reader.get_mut().deadline = Some(Instant::now() + header_timeout);
+            let req = parse_request(&mut reader);
+            reader.get_mut().deadline = None;
+            let _ = writer.get_ref().set_read_timeout(Some(header_timeout));
+            let req = req.and_then(|mut req| {
+                if req.expects_continue() { send_continue(&mut writer, &req, max_body_len)? };
+                read_body(&mut reader, &mut req, max_body_len)?;
+                Ok(req)
             });
+            let req = match req {
+                Ok(req) => req,
+                Err(err) => return send_error(&mut reader, &mut writer, &err),
+            };
+            let upstream = config.load().find_proxy(&req.path).map(str::to_owned);
+            if let Some(upstream) = upstream {
+                let upstream_stream = forward_request(&req, &upstream, &mut writer);
+                let Ok(upstream_stream) = upstream_stream.map_err(|err| eprintln!("Proxy request to '{}' failed: {}", upstream, err)) else { return };
+                let Ok(connection) = writer.into_inner().map_err(|err| eprintln!("{}", err.error())) else { return };
+                // The upstream decides when the response, or an upgraded connection, is done.
+                // Until then the hub passes it on, so that slow responses don't keep a worker.
+                let _ = connection.set_read_timeout(None);
+                let is_upgrade = req.headers().any(|h| matches!(h, Header::Upgrade(_)));
+                let added = self.websocket_hub().and_then(|hub| hub.add_tunnel(connection, upstream_stream, is_upgrade, reader.buffer().to_vec()));
+                if let Err(err) = added { eprintln!("{}", err) };
+                return;
+            }
+            if is_valid_websocket(&req) { 
+                let resp = match make_websocket_accept_response(&req) {
+                    Ok(resp) => resp,
+                    Err(err) => return send_error(&mut reader, &mut writer, &err),
+                };
+                let Ok(_) = write_response(&mut writer, &resp).map_err(|err| eprintln!("{}", err)) else { return };
+                let Ok(connection) = writer.into_inner().map_err(|err| eprintln!("{}", err.error())) else { return };
+                // WebSocket clients are often quiet for a long time, that is fine.
+                let _ = connection.set_read_timeout(None);
+                let added = self.websocket_hub().and_then(|hub| hub.add_client(connection, peer_addr, reader.buffer().to_vec()));
+                if let Err(err) = added { eprintln!("{}", err) };
+                return;
+            }
+            let Ok(_) = respond(config, &req, &mut writer).map_err(|err| eprintln!("{}", err)) else { return };
+            if !req.keep_alive() { return };
         }
-        Ok(())
+    }
+}
+
+/// How long to read what a client is still sending, after an error response, before the connection is closed.
+const LINGER_TIMEOUT: Duration = Duration::from_secs(2);
+/// How much of what a client is still sending to read, after an error response, before the connection is closed.
+const MAX_LINGER_LEN: usize = 1024 * 1024;
+
+/// Tell a client that waits for 100 Continue to send the body, or 413 if the body is too large.
+fn send_continue<W: Write>(writer: &mut BufWriter<W>, req: &Request, max_body_len: usize) -> Result<()> {
+    if !req.is_chunked() {
+        let Some(len) = req.content_length().filter(|len| *len > 0) else { return Ok(()) };
+        if len > max_body_len { return Err(HttpErrorKind::BodyTooLarge(len as u64).into()) };
+    }
+    write_response(writer, &make_http_response(StatusCode(100), vec![], None))
+}
+
+/// Respond to a request that could not be read or understood, if the client is still there to receive it.
+/// The connection is closed afterwards, since it is unknown where the next request starts.
+fn send_error<C: Connection>(reader: &mut BufReader<DeadlineReader<C>>, writer: &mut BufWriter<C>, err: &Error) {
+    let Error::HttpError(kind) = err else { return };
+    if let HttpErrorKind::ParseError(ParseErrorKind::IncompleteError) = kind { return };
+    let resp = make_http_response(kind.status_code(), vec![Header::connection("close")], None);
+    let Ok(_) = write_response(writer, &resp) else { return };
+    // Closing with unread input makes the OS reset the connection, which can discard the response before
+    // the client has read it. So read what the client is still sending first, within limits, see RFC 9112 section 9.6
+    let Ok(_) = writer.get_ref().shutdown_write() else { return };
+    reader.get_mut().deadline = Some(Instant::now() + LINGER_TIMEOUT);
+    let mut buf = [0u8; 8192];
+    let mut drained = 0;
+    while drained < MAX_LINGER_LEN {
+        match reader.read(&mut buf) {
+            Ok(0) | Err(_) => break,
+            Ok(len) => drained += len,
+        }
+    }
+}
+
+/// Reads from a connection. Once deadline has passed, reads fail with TimedOut, however often data arrives.
+struct DeadlineReader<C> {
+    connection: C,
+    deadline: Option<Instant>,
+}
+
+impl<C: Connection> Read for DeadlineReader<C> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Some(deadline) = self.deadline {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            if timeout.is_zero() { return Err(io::ErrorKind::TimedOut.into()) };
+            self.connection.set_read_timeout(Some(timeout))?;
+        }
+        self.connection.read(buf)
+    }
+}
+
+/// Respond to a plain http request.
+fn respond<W: Write>(config: &ConfigSnapshots, req: &Request, writer: &mut BufWriter<W>) -> Result<()> {
+    let config = config.load();
+    let cors_headers = config.cors.as_ref().map(|cors| cors.response_headers(req)).unwrap_or_default();
+    let resp = match config.resolve(req) {
+        Resolved::Found(_, Endpoint { response_action: Some(ResponseAction::Handler(handler)), .. }) => {
+            let mut resp = handler(req);
+            if resp.status_code.allows_body() && !resp.headers.iter().any(|h| matches!(h, Header::ContentLength(_))) {
+                resp.headers.push(Header::ContentLength(resp.body.as_ref().map(|body| body.len()).unwrap_or(0)));
+            }
+            return send_response(writer, req, resp, cors_headers);
+        },
+        Resolved::Found(path, endpoint @ Endpoint { response_action: Some(ResponseAction::LazyLoad(file_path)), .. }) => {
+            match endpoint.loaded_file.get() {
+                Some((headers, body)) => make_content_response(&config, req, path, headers, body, &endpoint.encoded_bodies),
+                None => match load_file(&config, req, &endpoint.headers, file_path) {
+                    Some(LoadedFile::Streamed(resp)) => resp,
+                    Some(LoadedFile::Read(headers, body)) => {
+                        // Another request might have loaded it first, then that one is used.
+                        let _ = endpoint.loaded_file.set((headers, body));
+                        let (headers, body) = endpoint.loaded_file.get().unwrap();
+                        make_content_response(&config, req, path, headers, body, &endpoint.encoded_bodies)
+                    },
+                    None => make_http_response(StatusCode(404), vec![], None),
+                },
+            }
+        },
+        Resolved::Found(path, endpoint @ Endpoint { response_action: Some(ResponseAction::Content(body)), .. }) => 
+            make_content_response(&config, req, path, &endpoint.headers, body, &endpoint.encoded_bodies),
+        Resolved::MethodNotAllowed(methods) => {
+            let allow = methods.iter().map(|method| method.to_string()).collect::<Vec<_>>().join(", ");
+            // OPTIONS asks for the allowed methods, and is also used for CORS preflight requests.
+            let status_code = match req.request_type {
+                RequestType::OPTIONS => StatusCode(204),
+                _ => StatusCode(405),
+            };
+            make_http_response(status_code, vec![Header::allow(&allow)], None)
+        },
+        Resolved::Found(..) | Resolved::NotFound => 
+            make_http_response(StatusCode(404), vec![], None),
+    };
+    send_response(writer, req, resp, cors_headers)
+}
+
+enum LoadedFile {
+    /// Too large to keep in memory, so the response streams it from disk.
+    Streamed(Response<'static>),
+    /// Headers and body to keep in the endpoint.
+    Read(Vec<Header>, Vec<u8>),
+}
+
+/// Read a lazily loaded file, or prepare a response that streams it if it is larger than the stream threshold.
+fn load_file(config: &ServerConfig, req: &Request, headers: &[Header], file_path: &str) -> Option<LoadedFile> {
+    let metadata = fs::metadata(file_path).ok()?;
+    let mut headers = headers.to_vec();
+    if let Ok(modified) = metadata.modified() {
+        // Http dates only have second precision.
+        let secs = modified.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        headers.push(Header::LastModified(UNIX_EPOCH + Duration::from_secs(secs)));
+    }
+    if metadata.len() > config.stream_threshold {
+        let body = fs::File::open(file_path).and_then(Body::from_file).ok()?;
+        headers.push(Header::ContentLength(body.len()));
+        headers.push(Header::ETag(make_file_etag(metadata.len(), metadata.modified().unwrap_or(UNIX_EPOCH))));
+        headers.push(Header::accept_ranges("bytes"));
+        let ranges = requested_ranges(req, &headers);
+        return Some(LoadedFile::Streamed(make_validated_response(req, headers, body, ranges)));
+    }
+    let body = fs::read(file_path).ok()?;
+    headers.push(Header::ContentLength(body.len()));
+    headers.push(Header::ETag(make_etag(&body)));
+    Some(LoadedFile::Read(headers, body))
+}
+
+/// Write a response that tells the client whether the connection stays open afterwards.
+/// Responses to HEAD requests keep their headers, like Content-Length, but not the body.
+fn send_response<W: Write>(writer: &mut BufWriter<W>, req: &Request, mut resp: Response, cors_headers: Vec<Header>) -> Result<()> {
+    resp.headers.extend(cors_headers);
+    if let RequestType::HEAD = req.request_type { resp.body = None };
+    match (req.keep_alive(), &req.version) {
+        (false, _) => resp.headers.push(Header::connection("close")),
+        // Persistent connections are not the default in HTTP/1.0, so confirm it.
+        (true, Version::V1_0) => resp.headers.push(Header::connection("keep-alive")),
+        (true, Version::V1_1) => (),
+    }
+    write_response(writer, &resp)
+}
+
+/// Response for a body, compressed if the client accepts it, or 304 if the client already has it.
+/// Requests for a range of the body get it uncompressed, with 206 or 416.
+fn make_content_response<'a>(
+    config: &ServerConfig,
+    req: &Request,
+    path: &str,
+    headers: &[Header],
+    body: &'a [u8],
+    encoded_bodies: &'a EncodedBodies,
+) -> Response<'a> {
+    let is_compressible = config.compression && is_compressible(headers, body);
+    let ranges = requested_ranges(req, headers);
+    let mut headers = headers.to_vec();
+    headers.push(Header::accept_ranges("bytes"));
+    if is_compressible { headers.push(Header::vary("Accept-Encoding")) };
+    let encoding = req.preferred_encoding().filter(|_| is_compressible && ranges.is_none());
+    let body = match encoding {
+        Some(encoding) => {
+            // Concurrent requests for the same encoding wait for the first one to compress it.
+            let encoded_body = encoded_bodies.get(encoding).get_or_init(|| {
+                let etag = headers.iter().find_map(|h| match h { Header::ETag(etag) => Some(etag), _ => None });
+                let precompressed = config.precompressed_dir.as_ref()
+                    .zip(etag)
+                    .and_then(|(dir, etag)| read_precompressed(dir, path, etag, encoding));
+                precompressed.unwrap_or_else(|| encoding.compress(body))
+            });
+            headers = make_encoded_headers(&headers, encoding, encoded_body);
+            encoded_body
+        },
+        None => body,
+    };
+    make_validated_response(req, headers, body.into(), ranges)
+}
+
+/// 304 if the client already has the body, the requested range of it, or all of it.
+fn make_validated_response<'a>(req: &Request, headers: Vec<Header>, body: Body<'a>, ranges: Option<&[ByteRange]>) -> Response<'a> {
+    if is_not_modified(req, &headers) {
+        let headers = headers.into_iter()
+            .filter(|h| matches!(h, Header::ETag(_) | Header::LastModified(_) | Header::CacheControl(_) | Header::Vary(_)))
+            .collect();
+        make_http_response(StatusCode(304), headers, None)
+    } else if let Some(ranges) = ranges {
+        make_range_response(headers, body, ranges)
+    } else {
+        make_http_response(StatusCode(200), headers, Some(body))
     }
 }
@@ -6,10 +6,61 @@ use super::error::*;
 
 pub struct StatusCode (pub i32);
 impl StatusCode {
+    /// False for responses that never have a body, see RFC 9112 section 6.3
+    pub fn allows_body(&self) -> bool {
+        !matches!(self, StatusCode(100..=199) | StatusCode(204) | StatusCode(304))
+    }
     pub fn name(&self) -> &'static str {
+        // See RFC 9110 section 15, and RFC 6585 for 428, 429, 431 and 511
         match self {
+            StatusCode(100) => "Continue",
             StatusCode(101) => "Switching Protocols",
-            StatusCode(200..=299) => "OK",
+            StatusCode(103) => "Early Hints",
+            StatusCode(200) => "OK",
+            StatusCode(201) => "Created",
+            StatusCode(202) => "Accepted",
+            StatusCode(203) => "Non-Authoritative Information",
+            StatusCode(204) => "No Content",
+            StatusCode(205) => "Reset Content",
+            StatusCode(206) => "Partial Content",
+            StatusCode(300) => "Multiple Choices",
+            StatusCode(301) => "Moved Permanently",
+            StatusCode(302) => "Found",
+            StatusCode(303) => "See Other",
+            StatusCode(304) => "Not Modified",
+            StatusCode(307) => "Temporary Redirect",
+            StatusCode(308) => "Permanent Redirect",
+            StatusCode(400) => "Bad Request",
+            StatusCode(401) => "Unauthorized",
+            StatusCode(402) => "Payment Required",
+            StatusCode(403) => "Forbidden",
+            StatusCode(404) => "Not Found",
+            StatusCode(405) => "Method Not Allowed",
+            StatusCode(406) => "Not Acceptable",
+            StatusCode(407) => "Proxy Authentication Required",
+            StatusCode(408) => "Request Timeout",
+            StatusCode(409) => "Conflict",
+            StatusCode(410) => "Gone",
+            StatusCode(411) => "Length Required",
+            StatusCode(412) => "Precondition Failed",
+            StatusCode(413) => "Content Too Large",
+            StatusCode(414) => "URI Too Long",
+            StatusCode(415) => "Unsupported Media Type",
+            StatusCode(416) => "Range Not Satisfiable",
+            StatusCode(417) => "Expectation Failed",
+            StatusCode(421) => "Misdirected Request",
+            StatusCode(422) => "Unprocessable Content",
+            StatusCode(426) => "Upgrade Required",
+            StatusCode(428) => "Precondition Required",
+            StatusCode(429) => "Too Many Requests",
+            StatusCode(431) => "Request Header Fields Too Large",
+            StatusCode(500) => "Internal Server Error",
+            StatusCode(501) => "Not Implemented",
+            StatusCode(502) => "Bad Gateway",
+            StatusCode(503) => "Service Unavailable",
+            StatusCode(504) => "Gateway Timeout",
+            StatusCode(505) => "HTTP Version Not Supported",
+            StatusCode(511) => "Network Authentication Required",
             _ => "",
         }
     }
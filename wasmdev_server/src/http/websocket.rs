@@ -0,0 +1,370 @@
+use std::io::{Read, Write};
+use std::str::from_utf8;
+
+use super::error::*;
+
+/// Largest payload that fits in the 7-bit length field of a frame header.
+const MAX_SHORT_PAYLOAD_LEN: usize = 125;
+/// Largest message accepted from a client. Anything larger closes the connection.
+pub const MAX_MESSAGE_LEN: u64 = 16 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Opcode{ Continuation, Text, Binary, Close, Ping, Pong }
+impl Opcode {
+    pub fn as_u8(&self) -> u8 {
+        match self {
+            Self::Continuation => 0x0,
+            Self::Text => 0x1,
+            Self::Binary => 0x2,
+            Self::Close => 0x8,
+            Self::Ping => 0x9,
+            Self::Pong => 0xA,
+        }
+    }
+    pub fn from_u8(byte: u8) -> Option<Opcode> {
+        match byte {
+            0x0 => Some(Self::Continuation),
+            0x1 => Some(Self::Text),
+            0x2 => Some(Self::Binary),
+            0x8 => Some(Self::Close),
+            0x9 => Some(Self::Ping),
+            0xA => Some(Self::Pong),
+            _ => None,
+        }
+    }
+    pub fn is_control(&self) -> bool {
+        matches!(self, Self::Close | Self::Ping | Self::Pong)
+    }
+}
+
+/// Encode a server-to-client (unmasked) frame header, see RFC 6455 section 5.2
+pub fn encode_frame_header(fin: bool, opcode: Opcode, payload_len: usize) -> Vec<u8> {
+    let first_byte = if fin { 0x80 } else { 0x00 } | opcode.as_u8();
+    let mut header = vec![first_byte];
+    if payload_len <= MAX_SHORT_PAYLOAD_LEN {
+        header.push(payload_len as u8);
+    } else if payload_len <= u16::MAX as usize {
+        header.push(126);
+        header.extend_from_slice(&(payload_len as u16).to_be_bytes());
+    } else {
+        header.push(127);
+        header.extend_from_slice(&(payload_len as u64).to_be_bytes());
+    }
+    header
+}
+
+/// Write a single frame. Does not flush the writer.
+pub fn write_frame<T: Write>(writer: &mut T, fin: bool, opcode: Opcode, payload: &[u8]) -> Result<()> {
+    writer.write_all(&encode_frame_header(fin, opcode, payload.len()))?;
+    writer.write_all(payload)?;
+    Ok(())
+}
+
+/// Write a complete message as one frame and flush the writer.
+pub fn write_message<T: Write>(writer: &mut T, opcode: Opcode, payload: &[u8]) -> Result<()> {
+    write_frame(writer, true, opcode, payload)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Write a message split into frames of at most `fragment_size` bytes and flush the writer.
+/// Control frames can't be fragmented, so they are always sent as one frame.
+pub fn write_fragmented_message<T: Write>(writer: &mut T, opcode: Opcode, payload: &[u8], fragment_size: usize) -> Result<()> {
+    if opcode.is_control() || fragment_size == 0 || payload.len() <= fragment_size {
+        return write_message(writer, opcode, payload);
+    }
+    let fragment_count = payload.chunks(fragment_size).count();
+    for (i, fragment) in payload.chunks(fragment_size).enumerate() {
+        let opcode = if i == 0 { opcode } else { Opcode::Continuation };
+        write_frame(writer, i + 1 == fragment_count, opcode, fragment)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+pub struct Frame {
+    pub fin: bool,
+    pub opcode: Opcode,
+    pub masked: bool,
+    pub payload: Vec<u8>,
+}
+
+/// A complete (possibly defragmented) data message
+#[derive(Debug, Clone, PartialEq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+/// Apply or remove the masking key on a payload, see RFC 6455 section 5.3
+pub fn apply_mask(payload: &mut [u8], mask: [u8; 4]) {
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+}
+
+/// Read a single frame. A masked payload is unmasked before it is returned.
+pub fn read_frame<T: Read>(reader: &mut T) -> Result<Frame> {
+    let mut head = [0u8; 2];
+    reader.read_exact(&mut head)?;
+    let fin = head[0] & 0x80 != 0;
+    // No extensions are negotiated, so RSV1-3 must be zero, see RFC 6455 section 5.2
+    let reserved_bits = (head[0] & 0x70) >> 4;
+    if reserved_bits != 0 {
+        return Err(WebSocketErrorKind::ReservedBits(reserved_bits).into());
+    }
+    let opcode = Opcode::from_u8(head[0] & 0x0F)
+        .ok_or(WebSocketErrorKind::UnknownOpcode(head[0] & 0x0F))?;
+    let masked = head[1] & 0x80 != 0;
+    let payload_len = match head[1] & 0x7F {
+        126 => {
+            let mut len = [0u8; 2];
+            reader.read_exact(&mut len)?;
+            u16::from_be_bytes(len) as u64
+        },
+        127 => {
+            let mut len = [0u8; 8];
+            reader.read_exact(&mut len)?;
+            u64::from_be_bytes(len)
+        },
+        len => len as u64,
+    };
+    if opcode.is_control() && (!fin || payload_len > MAX_SHORT_PAYLOAD_LEN as u64) {
+        return Err(WebSocketErrorKind::InvalidControlFrame.into());
+    }
+    if payload_len > MAX_MESSAGE_LEN {
+        return Err(WebSocketErrorKind::MessageTooLarge(payload_len).into());
+    }
+    let mask = if masked {
+        let mut mask = [0u8; 4];
+        reader.read_exact(&mut mask)?;
+        Some(mask)
+    } else { None };
+    let mut payload = vec![0u8; payload_len as usize];
+    reader.read_exact(&mut payload)?;
+    if let Some(mask) = mask {
+        apply_mask(&mut payload, mask);
+    }
+    if opcode == Opcode::Close { validate_close_payload(&payload)? };
+    Ok(Frame { fin, opcode, masked, payload })
+}
+
+/// A close payload is empty, or a status code that may be sent in a close frame followed by an utf8 reason,
+/// see RFC 6455 section 5.5.1 and 7.4
+fn validate_close_payload(payload: &[u8]) -> Result<()> {
+    let Some((code, reason)) = payload.split_first_chunk::<2>() else {
+        if payload.is_empty() { return Ok(()) };
+        return Err(WebSocketErrorKind::InvalidCloseFrame.into());
+    };
+    match u16::from_be_bytes(*code) {
+        1000..=1003 | 1007..=1014 | 3000..=4999 => (),
+        _ => return Err(WebSocketErrorKind::InvalidCloseFrame.into()),
+    }
+    if from_utf8(reason).is_err() { return Err(WebSocketErrorKind::InvalidUtf8.into()) };
+    Ok(())
+}
+
+/// Length of the frame at the start of buf, including its header. None until enough of the header is there to tell.
+pub fn frame_len(buf: &[u8]) -> Option<u64> {
+    let [_, second, rest @ ..] = buf else { return None };
+    let mask_len = if second & 0x80 != 0 { 4 } else { 0 };
+    let (header_len, payload_len) = match second & 0x7F {
+        126 => (4, u16::from_be_bytes(rest.get(..2)?.try_into().ok()?) as u64),
+        127 => (10, u64::from_be_bytes(rest.get(..8)?.try_into().ok()?)),
+        len => (2, len as u64),
+    };
+    Some(payload_len.saturating_add(header_len + mask_len))
+}
+
+/// Collects data frames until a message is complete
+#[derive(Default)]
+pub struct MessageAssembler {
+    opcode: Option<Opcode>,
+    payload: Vec<u8>,
+}
+
+impl MessageAssembler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Push a data frame. Returns the message once its final frame has arrived.
+    pub fn push(&mut self, frame: Frame) -> Result<Option<Message>> {
+        let opcode = match (frame.opcode, self.opcode) {
+            (Opcode::Continuation, Some(opcode)) => opcode,
+            (Opcode::Continuation, None)         => return Err(WebSocketErrorKind::InvalidFragmentation.into()),
+            (_, Some(_))                         => return Err(WebSocketErrorKind::InvalidFragmentation.into()),
+            (opcode, None)                       => opcode,
+        };
+        let len = (self.payload.len() + frame.payload.len()) as u64;
+        if len > MAX_MESSAGE_LEN {
+            return Err(WebSocketErrorKind::MessageTooLarge(len).into());
+        }
+        self.payload.extend_from_slice(&frame.payload);
+        if !frame.fin {
+            self.opcode = Some(opcode);
+            return Ok(None);
+        }
+        self.opcode = None;
+        let payload = std::mem::take(&mut self.payload);
+        match opcode {
+            Opcode::Text => {
+                let Ok(text) = from_utf8(&payload) else { return Err(WebSocketErrorKind::InvalidUtf8.into()) };
+                Ok(Some(Message::Text(text.to_string())))
+            },
+            _ => Ok(Some(Message::Binary(payload))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frame_header_lengths() {
+        assert_eq!(encode_frame_header(true, Opcode::Text, 0), [0x81, 0]);
+        assert_eq!(encode_frame_header(true, Opcode::Text, 125), [0x81, 125]);
+        assert_eq!(encode_frame_header(true, Opcode::Binary, 126), [0x82, 126, 0, 126]);
+        assert_eq!(encode_frame_header(true, Opcode::Binary, 65535), [0x82, 126, 0xff, 0xff]);
+        assert_eq!(encode_frame_header(false, Opcode::Text, 65536), [0x01, 127, 0, 0, 0, 0, 0, 1, 0, 0]);
+    }
+
+    #[test]
+    fn fragmented_message() {
+        let mut frames = vec![];
+        write_fragmented_message(&mut frames, Opcode::Text, b"hello", 2).unwrap();
+        assert_eq!(frames, [0x01, 2, b'h', b'e', 0x00, 2, b'l', b'l', 0x80, 1, b'o']);
+    }
+
+    #[test]
+    fn control_frames_are_not_fragmented() {
+        let mut frames = vec![];
+        write_fragmented_message(&mut frames, Opcode::Ping, b"ping", 2).unwrap();
+        assert_eq!(frames, [0x89, 4, b'p', b'i', b'n', b'g']);
+    }
+
+    /// A frame as a browser sends it, masked.
+    fn client_frame(first_byte: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [0x37, 0xfa, 0x21, 0x3d];
+        let mut frame = encode_frame_header(false, Opcode::Continuation, payload.len());
+        frame[0] = first_byte;
+        frame[1] |= 0x80;
+        frame.extend_from_slice(&mask);
+        let start = frame.len();
+        frame.extend_from_slice(payload);
+        apply_mask(&mut frame[start..], mask);
+        frame
+    }
+
+    /// Close code that the error from reading bytes as a frame fails the connection with.
+    fn close_code_of(bytes: &[u8]) -> Option<u16> {
+        match read_frame(&mut &bytes[..]) {
+            Err(Error::WebSocketError(err)) => Some(err.close_code()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn frame_round_trip() {
+        for len in [0, 125, 126, 65535, 65536] {
+            let payload: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            let mut bytes = vec![];
+            write_frame(&mut bytes, true, Opcode::Binary, &payload).unwrap();
+            let frame = read_frame(&mut &bytes[..]).unwrap();
+            assert!(frame.fin && !frame.masked);
+            assert_eq!((frame.opcode, frame.payload.len()), (Opcode::Binary, len));
+            assert!(frame.payload == payload);
+        }
+    }
+
+    #[test]
+    fn masked_frame_round_trip() {
+        for len in [0, 125, 126, 65535, 65536] {
+            let payload: Vec<u8> = (0..len).map(|i| (i * 7) as u8).collect();
+            let bytes = client_frame(0x82, &payload);
+            if len > 0 { assert!(bytes[bytes.len() - len..] != payload[..]) };
+            let frame = read_frame(&mut &bytes[..]).unwrap();
+            assert!(frame.masked);
+            assert!(frame.payload == payload);
+        }
+    }
+
+    #[test]
+    fn fragments_with_control_frames_in_between() {
+        let mut bytes = client_frame(0x01, b"Hello, ");
+        bytes.extend(client_frame(0x89, b"ping"));
+        bytes.extend(client_frame(0x00, b"fragmented "));
+        bytes.extend(client_frame(0x8A, b""));
+        bytes.extend(client_frame(0x80, b"world"));
+        let mut reader = &bytes[..];
+        let mut assembler = MessageAssembler::new();
+        let mut control_frames = vec![];
+        let mut messages = vec![];
+        while !reader.is_empty() {
+            let frame = read_frame(&mut reader).unwrap();
+            if frame.opcode.is_control() {
+                control_frames.push(frame.opcode);
+            } else if let Some(message) = assembler.push(frame).unwrap() {
+                messages.push(message);
+            }
+        }
+        assert_eq!(control_frames, [Opcode::Ping, Opcode::Pong]);
+        assert_eq!(messages, [Message::Text("Hello, fragmented world".into())]);
+    }
+
+    #[test]
+    fn fragments_out_of_order() {
+        let mut assembler = MessageAssembler::new();
+        let continuation = Frame { fin: true, opcode: Opcode::Continuation, masked: true, payload: vec![] };
+        assert!(assembler.push(continuation).is_err());
+        let first = Frame { fin: false, opcode: Opcode::Text, masked: true, payload: vec![] };
+        let second = Frame { fin: true, opcode: Opcode::Binary, masked: true, payload: vec![] };
+        assert!(assembler.push(first).unwrap().is_none());
+        assert!(assembler.push(second).is_err());
+    }
+
+    #[test]
+    fn reserved_bits() {
+        assert_eq!(close_code_of(&client_frame(0xC1, b"hi")), Some(1002));
+        assert_eq!(close_code_of(&client_frame(0x91, b"hi")), Some(1002));
+    }
+
+    #[test]
+    fn close_payloads() {
+        assert!(read_frame(&mut &client_frame(0x88, b"")[..]).is_ok());
+        assert!(read_frame(&mut &client_frame(0x88, &1000u16.to_be_bytes())[..]).is_ok());
+        assert!(read_frame(&mut &client_frame(0x88, b"\x0f\xa0going away")[..]).is_ok());
+        assert_eq!(close_code_of(&client_frame(0x88, &[0x03])), Some(1002));
+        for code in [0u16, 999, 1004, 1005, 1006, 1015, 2999, 5000] {
+            assert_eq!(close_code_of(&client_frame(0x88, &code.to_be_bytes())), Some(1002), "close code {code}");
+        }
+        assert_eq!(close_code_of(&client_frame(0x88, b"\x03\xe8\xff")), Some(1007));
+    }
+
+    #[test]
+    fn invalid_control_frames() {
+        assert_eq!(close_code_of(&client_frame(0x09, b"ping")), Some(1002));
+        assert_eq!(close_code_of(&client_frame(0x89, &[0; 126])), Some(1002));
+        assert_eq!(close_code_of(&client_frame(0x83, b"")), Some(1002));
+    }
+
+    #[test]
+    fn oversized_messages() {
+        let mut header = encode_frame_header(true, Opcode::Binary, MAX_MESSAGE_LEN as usize + 1);
+        header[1] |= 0x80;
+        assert_eq!(close_code_of(&header), Some(1009));
+        let half = MAX_MESSAGE_LEN as usize / 2 + 1;
+        let mut assembler = MessageAssembler::new();
+        let first = Frame { fin: false, opcode: Opcode::Binary, masked: true, payload: vec![0; half] };
+        let second = Frame { fin: true, opcode: Opcode::Continuation, masked: true, payload: vec![0; half] };
+        assert!(assembler.push(first).unwrap().is_none());
+        assert!(matches!(assembler.push(second), Err(Error::WebSocketError(WebSocketErrorKind::MessageTooLarge(_)))));
+    }
+
+    #[test]
+    fn invalid_utf8() {
+        let mut assembler = MessageAssembler::new();
+        let frame = Frame { fin: true, opcode: Opcode::Text, masked: true, payload: vec![b'a', 0xc3] };
+        assert!(matches!(assembler.push(frame), Err(Error::WebSocketError(WebSocketErrorKind::InvalidUtf8))));
+    }
+}
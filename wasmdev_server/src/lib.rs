@@ -6,6 +6,9 @@ pub mod http;
 #[cfg(not(target_family = "wasm"))]
 pub mod utils;
 
+#[cfg(all(not(target_family = "wasm"), feature = "test-support"))]
+pub mod test_support;
+
 #[cfg(not(target_family = "wasm"))]
 pub use http::{Server, ServerConfig};
 
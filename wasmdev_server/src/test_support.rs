@@ -0,0 +1,283 @@
+//! In-memory connections, to test the configuration of a Server without binding a port. Enabled by the "test-support" feature.
+
+use std::collections::VecDeque;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::http::websocket::{self, apply_mask, encode_frame_header, Frame, Message, MessageAssembler, Opcode};
+use crate::http::{Body, Connection, Error, Header, Response, Result, Server, StatusCode, Version};
+
+/// How long a TestClient waits for the server, by default.
+pub const DEFAULT_TEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Port of the next client, so that clients of the WebSocket message handler can be told apart.
+static NEXT_CLIENT_PORT: AtomicU16 = AtomicU16::new(49152);
+
+/// Bytes written to one end of a connection, until they are read from the other.
+#[derive(Default)]
+struct Pipe {
+    state: Mutex<PipeState>,
+    changed: Condvar,
+}
+
+#[derive(Default)]
+struct PipeState {
+    bytes: VecDeque<u8>,
+    closed: bool,
+}
+
+impl Pipe {
+    fn read(&self, buf: &mut [u8], timeout: Option<Duration>) -> io::Result<usize> {
+        if buf.is_empty() { return Ok(0) };
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let mut state = self.state.lock().unwrap();
+        while state.bytes.is_empty() && !state.closed {
+            state = match deadline {
+                None => self.changed.wait(state).unwrap(),
+                Some(deadline) => {
+                    let timeout = deadline.saturating_duration_since(Instant::now());
+                    if timeout.is_zero() { return Err(io::ErrorKind::WouldBlock.into()) };
+                    self.changed.wait_timeout(state, timeout).unwrap().0
+                },
+            };
+        }
+        let len = buf.len().min(state.bytes.len());
+        for (dst, src) in buf.iter_mut().zip(state.bytes.drain(..len)) {
+            *dst = src;
+        }
+        Ok(len)
+    }
+
+    fn write(&self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        if state.closed { return Err(io::ErrorKind::BrokenPipe.into()) };
+        state.bytes.extend(buf);
+        self.changed.notify_all();
+        Ok(buf.len())
+    }
+
+    fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.changed.notify_all();
+    }
+}
+
+struct End {
+    incoming: Arc<Pipe>,
+    outgoing: Arc<Pipe>,
+    peer_addr: SocketAddr,
+    read_timeout: Mutex<Option<Duration>>,
+}
+
+impl Drop for End {
+    fn drop(&mut self) {
+        // The other end reads what is left, then sees the connection close.
+        self.incoming.close();
+        self.outgoing.close();
+    }
+}
+
+/// One end of an in-memory connection. Clones are handles to the same end, which closes when the last one is dropped,
+/// like a TcpStream.
+#[derive(Clone)]
+pub struct MemoryConnection {
+    end: Arc<End>,
+}
+
+impl MemoryConnection {
+    /// A client end and a server end, connected to each other.
+    /// The server end has a made up localhost address as peer, that is unique for each pair.
+    pub fn pair() -> (MemoryConnection, MemoryConnection) {
+        let to_server = Arc::new(Pipe::default());
+        let to_client = Arc::new(Pipe::default());
+        let client_addr = SocketAddr::from((Ipv4Addr::LOCALHOST, NEXT_CLIENT_PORT.fetch_add(1, Ordering::Relaxed)));
+        let server_addr = SocketAddr::from((Ipv4Addr::LOCALHOST, 80));
+        let end = |incoming: &Arc<Pipe>, outgoing: &Arc<Pipe>, peer_addr| MemoryConnection {
+            end: Arc::new(End { incoming: incoming.clone(), outgoing: outgoing.clone(), peer_addr, read_timeout: Mutex::new(None) }),
+        };
+        (end(&to_client, &to_server, server_addr), end(&to_server, &to_client, client_addr))
+    }
+}
+
+impl Read for MemoryConnection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let timeout = *self.end.read_timeout.lock().unwrap();
+        self.end.incoming.read(buf, timeout)
+    }
+}
+
+impl Write for MemoryConnection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.end.outgoing.write(buf)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Connection for MemoryConnection {
+    fn try_clone(&self) -> io::Result<Self> {
+        Ok(self.clone())
+    }
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.end.peer_addr)
+    }
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        *self.end.read_timeout.lock().unwrap() = timeout;
+        Ok(())
+    }
+    fn shutdown(&self) -> io::Result<()> {
+        self.end.incoming.close();
+        self.end.outgoing.close();
+        Ok(())
+    }
+    fn shutdown_write(&self) -> io::Result<()> {
+        self.end.outgoing.close();
+        Ok(())
+    }
+}
+
+/// A client with an in-memory connection to a server. The server handles it on a thread of its own,
+/// like a connection accepted by Server::listen, so requests go through the same code as real ones.
+pub struct TestClient {
+    reader: BufReader<MemoryConnection>,
+    writer: MemoryConnection,
+    addr: SocketAddr,
+    assembler: MessageAssembler,
+}
+
+impl TestClient {
+    /// Connect to server. Reads fail with WouldBlock if the server does not answer within DEFAULT_TEST_TIMEOUT.
+    pub fn connect(server: &Server) -> TestClient {
+        let (client, connection) = MemoryConnection::pair();
+        let addr = connection.end.peer_addr;
+        let server = server.clone();
+        thread::spawn(move || server.handle_connection(connection));
+        let _ = client.set_read_timeout(Some(DEFAULT_TEST_TIMEOUT));
+        TestClient { reader: BufReader::new(client.clone()), writer: client, addr, assembler: MessageAssembler::new() }
+    }
+
+    /// Address of the client, as the WebSocket message handler sees it.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// How long to wait for the server. None waits forever.
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) {
+        let _ = self.writer.set_read_timeout(timeout);
+    }
+
+    /// Send raw bytes, like a request that is split up or malformed.
+    pub fn send(&mut self, bytes: &[u8]) -> Result<()> {
+        self.writer.write_all(bytes)?;
+        Ok(())
+    }
+
+    /// Send a raw request, like "GET / HTTP/1.1\r\nHost: localhost\r\n\r\n", and read the response to it.
+    pub fn request(&mut self, request: &[u8]) -> Result<Response<'static>> {
+        self.send(request)?;
+        self.read_response_to(request.starts_with(b"HEAD "))
+    }
+
+    /// Send a GET request for path and read the response to it.
+    pub fn get(&mut self, path: &str) -> Result<Response<'static>> {
+        self.request(format!("GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n").as_bytes())
+    }
+
+    /// Read the next response. Use request for HEAD requests, since their responses have no body.
+    pub fn read_response(&mut self) -> Result<Response<'static>> {
+        self.read_response_to(false)
+    }
+
+    fn read_response_to(&mut self, is_head: bool) -> Result<Response<'static>> {
+        let status_line = self.read_line()?;
+        let mut parts = status_line.splitn(3, ' ');
+        let version: Version = parts.next().unwrap_or_default().parse()?;
+        let status_code: StatusCode = parts.next().unwrap_or_default().parse()?;
+        let mut headers = vec![];
+        loop {
+            let line = self.read_line()?;
+            if line.is_empty() { break };
+            headers.push(line.parse::<Header>()?);
+        }
+        let content_length = headers.iter().find_map(|h| match h { Header::ContentLength(len) => Some(*len), _ => None });
+        let body = match (is_head || !status_code.allows_body(), content_length) {
+            (true, _) => None,
+            (false, Some(len)) => {
+                let mut body = vec![0u8; len];
+                self.reader.read_exact(&mut body)?;
+                Some(body)
+            },
+            // The server closes the connection at the end of the body.
+            (false, None) => {
+                let mut body = vec![];
+                self.reader.read_to_end(&mut body)?;
+                Some(body)
+            },
+        };
+        Ok(Response { version, status_code, headers, body: body.map(Body::from) })
+    }
+
+    fn read_line(&mut self) -> Result<String> {
+        let mut line = String::new();
+        if self.reader.read_line(&mut line)? == 0 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        Ok(line.trim_end_matches(['\r', '\n']).to_owned())
+    }
+
+    /// Upgrade the connection to WebSocket at path. Returns the response, which is 101 if it worked.
+    pub fn upgrade(&mut self, path: &str) -> Result<Response<'static>> {
+        let request = format!(
+            "GET {path} HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+             Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n"
+        );
+        self.request(request.as_bytes())
+    }
+
+    /// Send a single frame, masked like frames from a browser.
+    pub fn send_frame(&mut self, fin: bool, opcode: Opcode, payload: &[u8]) -> Result<()> {
+        let mask = [0x37, 0xfa, 0x21, 0x3d];
+        let mut frame = encode_frame_header(fin, opcode, payload.len());
+        frame[1] |= 0x80;
+        frame.extend_from_slice(&mask);
+        let start = frame.len();
+        frame.extend_from_slice(payload);
+        apply_mask(&mut frame[start..], mask);
+        self.send(&frame)
+    }
+
+    /// Send a complete message as one frame.
+    pub fn send_message(&mut self, message: &Message) -> Result<()> {
+        match message {
+            Message::Text(text) => self.send_frame(true, Opcode::Text, text.as_bytes()),
+            Message::Binary(bytes) => self.send_frame(true, Opcode::Binary, bytes),
+        }
+    }
+
+    /// Read the next frame, control frames included.
+    pub fn read_frame(&mut self) -> Result<Frame> {
+        websocket::read_frame(&mut self.reader)
+    }
+
+    /// Read frames until a complete data message has arrived. Pings and pongs are skipped.
+    /// Returns None when the server closes the connection.
+    pub fn read_message(&mut self) -> Result<Option<Message>> {
+        loop {
+            let frame = match self.read_frame() {
+                Ok(frame) => frame,
+                Err(Error::IOError(io::ErrorKind::UnexpectedEof)) => return Ok(None),
+                Err(err) => return Err(err),
+            };
+            match frame.opcode {
+                Opcode::Close => return Ok(None),
+                Opcode::Ping | Opcode::Pong => (),
+                _ => if let Some(message) = self.assembler.push(frame)? { return Ok(Some(message)) },
+            }
+        }
+    }
+}
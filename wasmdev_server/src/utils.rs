@@ -1,4 +1,13 @@
+use std::collections::HashSet;
+use std::net::SocketAddr;
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use wasmdev_core::code::CancelToken;
+use wasmdev_core::fs::FileFilter;
+use crate::http::websocket::Message;
 
 pub fn hash_bytes(bin: &[u8]) -> u32 {
     let mut a = 1u32;
@@ -13,92 +22,312 @@ pub fn hash_bytes(bin: &[u8]) -> u32 {
     res
 }
 
+/// File-system change reported by a watcher
+#[derive(Debug, Clone, PartialEq)]
+pub enum FileEvent {
+    Created(PathBuf),
+    Modified(PathBuf),
+    Removed(PathBuf),
+    Renamed(PathBuf, PathBuf),
+}
+
+impl FileEvent {
+    /// All paths affected by the event
+    pub fn paths(&self) -> Vec<&Path> {
+        match self {
+            Self::Created(path) | Self::Modified(path) | Self::Removed(path) => vec![path],
+            Self::Renamed(from, to) => vec![from, to],
+        }
+    }
+}
+
 pub trait EventHandler: Send + 'static {
-    fn handle_event(&mut self, files: Vec<PathBuf>);
+    fn handle_event(&mut self, events: Vec<FileEvent>);
 }
 
-impl<'a, F> EventHandler for F
+impl<F> EventHandler for F
 where
-    F: FnMut(Vec<PathBuf>) + Send + 'static,
+    F: FnMut(Vec<FileEvent>) + Send + 'static,
 {
-    fn handle_event(&mut self, files: Vec<PathBuf>) {
-        (self)(files);
+    fn handle_event(&mut self, events: Vec<FileEvent>) {
+        (self)(events);
+    }
+}
+
+#[cfg(not(target_family = "wasm"))]
+fn to_file_events(event: notify::Event) -> Vec<FileEvent> {
+    use notify::event::{EventKind, ModifyKind, RenameMode};
+
+    let mut paths = event.paths.into_iter();
+    match event.kind {
+        EventKind::Create(_) => paths.map(FileEvent::Created).collect(),
+        EventKind::Remove(_) => paths.map(FileEvent::Removed).collect(),
+        EventKind::Modify(ModifyKind::Name(RenameMode::Both)) => match (paths.next(), paths.next()) {
+            (Some(from), Some(to)) => vec![FileEvent::Renamed(from, to)],
+            _ => vec![],
+        },
+        EventKind::Modify(ModifyKind::Name(RenameMode::From)) => paths.map(FileEvent::Removed).collect(),
+        EventKind::Modify(ModifyKind::Name(RenameMode::To)) => paths.map(FileEvent::Created).collect(),
+        // Some platforms don't tell which side of the rename a path is, so check if it still exists.
+        EventKind::Modify(ModifyKind::Name(_)) => paths
+            .map(|p| if p.exists() { FileEvent::Created(p) } else { FileEvent::Removed(p) })
+            .collect(),
+        EventKind::Modify(_) => paths.map(FileEvent::Modified).collect(),
+        _ => vec![],
     }
 }
 
-/// This function wraps notify crate with some logic that tries to avoid duplicate events after one-another
+/// Poll interval used when the native watcher fails and no interval is configured.
+pub const DEFAULT_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// File-system notification backend used by make_watcher
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchBackend {
+    /// Notifications from the OS (inotify, FSEvents, ...). Falls back to polling if it can't be started.
+    Native,
+    /// Scan for changes with an interval. Works on network filesystems and bind-mounted container volumes.
+    Poll(Duration),
+}
+
+/// Options for make_watcher
+#[derive(Debug, Clone)]
+pub struct WatchConfig {
+    /// Events are collected until no new event has arrived for this long, then handled as one batch.
+    pub debounce: Duration,
+    pub backend: WatchBackend,
+    /// Only report events for paths included by this filter
+    pub filter: Option<Arc<FileFilter>>,
+}
+
+impl Default for WatchConfig {
+    fn default() -> Self {
+        WatchConfig { debounce: Duration::from_millis(100), backend: WatchBackend::Native, filter: None }
+    }
+}
+
+impl WatchConfig {
+    /// Let env vars override the backend, without rebuilding the app:
+    /// * WASMDEV_POLL: "true" or "1" to use polling, "false" or "0" to use native notifications.
+    /// * WASMDEV_POLL_INTERVAL: poll interval in milliseconds, implies WASMDEV_POLL=true.
+    pub fn with_env_overrides(mut self) -> Self {
+        let interval = match self.backend {
+            WatchBackend::Poll(interval) => interval,
+            WatchBackend::Native => DEFAULT_POLL_INTERVAL,
+        };
+        match std::env::var("WASMDEV_POLL").as_deref() {
+            Ok("true" | "1") => self.backend = WatchBackend::Poll(interval),
+            Ok("false" | "0") => self.backend = WatchBackend::Native,
+            _ => (),
+        }
+        if let Some(millis) = std::env::var("WASMDEV_POLL_INTERVAL").ok().and_then(|ms| ms.parse().ok()) {
+            self.backend = WatchBackend::Poll(Duration::from_millis(millis));
+        }
+        self
+    }
+}
+
+fn filter_event(filter: &FileFilter, event: FileEvent) -> Option<FileEvent> {
+    match event {
+        FileEvent::Renamed(from, to) => match (filter.is_included(&from), filter.is_included(&to)) {
+            (true, true)   => Some(FileEvent::Renamed(from, to)),
+            (true, false)  => Some(FileEvent::Removed(from)),
+            (false, true)  => Some(FileEvent::Created(to)),
+            (false, false) => None,
+        },
+        event => {
+            let is_included = event.paths().iter().all(|path| filter.is_included(path));
+            if is_included { Some(event) } else { None }
+        },
+    }
+}
+
+#[cfg(not(target_family = "wasm"))]
+pub type FileWatcher = Box<dyn notify::Watcher + Send>;
+
+/// This function wraps notify crate with some logic that coalesces bursts of events into one batch.
 /// It also defaults to a Recursive watcher.
 #[cfg(not(target_family = "wasm"))]
-pub fn make_watcher<P: AsRef<Path>>(path: P, mut event_handler: impl EventHandler) -> Option<impl notify::Watcher> {
-    use notify::{recommended_watcher, RecursiveMode, Result, Watcher};
-    use notify::event::{Event, EventKind};
-    use std::sync::{Arc, RwLock};
-    use std::sync::mpsc::channel;
+pub fn make_watcher<P: AsRef<Path>>(path: P, config: &WatchConfig, event_handler: impl EventHandler) -> Option<FileWatcher> {
+    use notify::RecursiveMode;
+
+    let path = path.as_ref().to_path_buf();
+    make_debounced_watcher(config, event_handler, |_| true, move |watcher| {
+        watcher.watch(&path, RecursiveMode::Recursive)
+    })
+}
+
+/// Same as make_watcher, but watches several files and directories at once.
+/// Files are watched through their parent directory, so that editors replacing the file on save are noticed.
+/// Paths that can't be watched are skipped, returns None if no path could be watched.
+#[cfg(not(target_family = "wasm"))]
+pub fn make_paths_watcher(paths: &[PathBuf], config: &WatchConfig, event_handler: impl EventHandler) -> Option<FileWatcher> {
+    use notify::RecursiveMode;
+
+    let dirs:  Vec<PathBuf> = paths.iter().filter(|path| path.is_dir()).cloned().collect();
+    let files: Vec<PathBuf> = paths.iter().filter(|path| !path.is_dir()).cloned().collect();
+    let parents: HashSet<PathBuf> = files.iter().filter_map(|file| file.parent()).map(Path::to_path_buf).collect();
+    let is_watched = {
+        let (dirs, files) = (dirs.clone(), files);
+        move |path: &Path| files.iter().any(|file| file == path) || dirs.iter().any(|dir| path.starts_with(dir))
+    };
+    make_debounced_watcher(config, event_handler, is_watched, move |watcher| {
+        let mut result = Err(notify::Error::path_not_found());
+        for dir in &dirs {
+            result = watcher.watch(dir, RecursiveMode::Recursive).or(result);
+        }
+        for parent in &parents {
+            result = watcher.watch(parent, RecursiveMode::NonRecursive).or(result);
+        }
+        result
+    })
+}
+
+#[cfg(not(target_family = "wasm"))]
+fn make_debounced_watcher(
+    config: &WatchConfig, 
+    mut event_handler: impl EventHandler, 
+    is_watched: impl Fn(&Path) -> bool + Send + Sync + 'static,
+    watch: impl Fn(&mut dyn notify::Watcher) -> notify::Result<()>,
+) -> Option<FileWatcher> {
+    use notify::{Config, ErrorKind, PollWatcher, RecommendedWatcher, Result, Watcher};
+    use notify::event::Event;
+    use std::sync::mpsc::{channel, RecvTimeoutError};
     use std::thread;
 
-    let path = path.as_ref();
-    let (event_sender, event_receiver) = channel::<Event>();
-    let active_event = Arc::new(RwLock::new(None));
-
-    let hash_event = |event: &Event| event.paths.iter()
-        .filter_map(|p| p.to_str())
-        .map(|s| hash_bytes(s.as_bytes()))
-        .fold(0u32, |sum, v| sum.wrapping_add(v));
-
-    {
-        let active_event = active_event.clone();
-        thread::spawn(move || {
-            for event in event_receiver.iter() {
-                *(active_event.write().unwrap()) = Some(hash_event(&event));
-                event_handler.handle_event(event.paths);
-                *(active_event.write().unwrap()) = None;
+    let debounce = config.debounce;
+    let filter = config.filter.clone();
+    let is_watched = Arc::new(is_watched);
+    let (event_sender, event_receiver) = channel::<Vec<FileEvent>>();
+
+    thread::spawn(move || {
+        while let Ok(events) = event_receiver.recv() {
+            let mut batch = vec![];
+            let mut add_to_batch = |events: Vec<FileEvent>| for event in events {
+                if !batch.contains(&event) { batch.push(event) }
+            };
+            add_to_batch(events);
+            loop {
+                match event_receiver.recv_timeout(debounce) {
+                    Ok(events) => add_to_batch(events),
+                    Err(RecvTimeoutError::Timeout) => break,
+                    Err(RecvTimeoutError::Disconnected) => return,
+                }
             }
-        });
-    }
+            event_handler.handle_event(batch);
+        }
+    });
 
-    let mut last_event = None;
-    let mut watcher = recommended_watcher(move |event: Result<Event>| -> () {
-        let Ok(event) = event                 else { return };
-        let EventKind::Modify(_) = event.kind else { return };
+    // A fallback watcher needs its own callback, so make it possible to create more than one.
+    let make_callback = || {
+        let (event_sender, filter, is_watched) = (event_sender.clone(), filter.clone(), is_watched.clone());
+        move |event: Result<Event>| {
+            let Ok(event) = event else { return };
+            let events: Vec<FileEvent> = to_file_events(event).into_iter()
+                .filter(|event| event.paths().iter().any(|path| is_watched(path)))
+                // The poll watcher reports a new mtime of a directory when its entries change, the entries are reported anyway.
+                .filter(|event| !matches!(event, FileEvent::Modified(path) if path.is_dir()))
+                .filter_map(|event| match &filter {
+                    Some(filter) => filter_event(filter, event),
+                    None => Some(event),
+                })
+                .collect();
+            if events.is_empty() { return };
+            let _ = event_sender.send(events);
+        }
+    };
 
-        let active_event_hash = *active_event.read().unwrap();
-        let hash = Some(hash_event(&event));
+    let poll_interval = match config.backend {
+        WatchBackend::Native => {
+            let watcher = RecommendedWatcher::new(make_callback(), Config::default())
+                .and_then(|mut watcher| watch(&mut watcher).map(|_| watcher));
+            match watcher {
+                Ok(watcher) => return Some(Box::new(watcher)),
+                // Nothing to watch, polling won't help.
+                Err(err) if matches!(err.kind, ErrorKind::PathNotFound) => return None,
+                Err(err) => {
+                    eprintln!("\x1b[0m\x1b[0m\x1b[1m\x1b[33m     Warning\x1b[0m Native file watcher failed, falling back to polling, {}", err);
+                    DEFAULT_POLL_INTERVAL
+                },
+            }
+        },
+        WatchBackend::Poll(interval) => interval,
+    };
+    let mut watcher = PollWatcher::new(make_callback(), Config::default().with_poll_interval(poll_interval)).ok()?;
+    watch(&mut watcher).ok()?;
+    Some(Box::new(watcher))
+}
+
+#[derive(Default)]
+struct TaskState {
+    pending: bool,
+    running: Option<CancelToken>,
+}
 
-        if active_event_hash == hash                         { return }
-        if active_event_hash.is_some() && last_event == hash { return }
-        if active_event_hash.is_none() {
-            // Ensure we have an active event
-            *(active_event.write().unwrap()) = hash;
+/// Runs a task on a background thread. Restarting it while it runs cancels the current run and
+/// starts over, restarts that arrive before the task has started are coalesced into one run.
+#[derive(Clone)]
+pub struct RestartableTask {
+    state: Arc<(Mutex<TaskState>, Condvar)>,
+}
+
+impl RestartableTask {
+    pub fn new(mut task: impl FnMut(&CancelToken) + Send + 'static) -> Self {
+        let state = Arc::new((Mutex::new(TaskState::default()), Condvar::new()));
+        {
+            let state = state.clone();
+            thread::spawn(move || loop {
+                let cancel = {
+                    let (lock, condvar) = &*state;
+                    let mut task_state = condvar
+                        .wait_while(lock.lock().unwrap(), |task_state| !task_state.pending)
+                        .unwrap();
+                    let cancel = CancelToken::new();
+                    task_state.pending = false;
+                    task_state.running = Some(cancel.clone());
+                    cancel
+                };
+                task(&cancel);
+                state.0.lock().unwrap().running = None;
+            });
+        }
+        RestartableTask { state }
+    }
+    pub fn restart(&self) {
+        let (lock, condvar) = &*self.state;
+        let mut task_state = lock.lock().unwrap();
+        if let Some(cancel) = &task_state.running {
+            cancel.cancel();
         }
-        last_event = hash;
-        let _ = event_sender.send(event);
-    }).ok()?;
+        task_state.pending = true;
+        condvar.notify_one();
+    }
+}
 
-    watcher.watch(path, RecursiveMode::Recursive).ok()?;
-    Some(watcher)
+/// Print console output forwarded by the injected client script, formatted as "console <level> <text>"
+pub fn print_client_message(addr: SocketAddr, message: Message) {
+    let Message::Text(text) = message else { return };
+    let Some(text) = text.strip_prefix("console ") else { return };
+    let (level, text) = text.split_once(' ').unwrap_or((text, ""));
+    let (label, color) = match level {
+        "warn"  => ("Warn",  "33"),
+        "error" => ("Error", "31"),
+        "panic" => ("Panic", "31"),
+        "info"  => ("Info",  "32"),
+        "debug" => ("Debug", "32"),
+        _       => ("Log",   "32"),
+    };
+    let text = text.replace('\n', "\n             ");
+    eprintln!("\x1b[0m\x1b[0m\x1b[1m\x1b[{color}m{label:>12}\x1b[0m {addr}: {text}");
 }
 
-pub struct Deferred <T: Fn() -> ()>{
+pub struct Deferred <T: Fn()>{
     pub f: T,
 }
 
-impl<T: Fn() -> ()> Drop for Deferred<T> {
+impl<T: Fn()> Drop for Deferred<T> {
     fn drop(&mut self) {
        let s: &Self = self;
        let f = &(s.f);
        f();
     }
 }
-
-macro_rules! defer_expr { ($e: expr) => { $e } } // tt hack
-macro_rules! defer {
-    ( $($s:tt)* ) => {
-        let _deferred = crate::utils::Deferred { f: || {
-            crate::utils::defer_expr!({ $($s)* })
-        }}; 
-    };
-    () => {};
-}
-
-pub(crate) use defer_expr;
-pub(crate) use defer;
\ No newline at end of file
@@ -0,0 +1,171 @@
+use std::sync::mpsc;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use wasmdev_server::http::websocket::{Message, Opcode};
+use wasmdev_server::http::Header;
+use wasmdev_server::test_support::TestClient;
+use wasmdev_server::prelude::*;
+use wasmdev_server::Server;
+
+fn hello_server() -> Server {
+    let server = Server::new();
+    server.configure(|config| {
+        config.on_get_request("/hello").set_response_body(b"Hello".to_vec()).build();
+        config.on_get_request("/world").set_response_body(b"World!".to_vec()).build();
+    });
+    server
+}
+
+fn header(headers: &[Header], name: &str) -> Option<String> {
+    headers.iter().find_map(|h| {
+        let line = h.to_string();
+        let (key, value) = line.split_once(": ")?;
+        key.eq_ignore_ascii_case(name).then(|| value.to_owned())
+    })
+}
+
+fn upgraded_client(server: &Server) -> TestClient {
+    let mut client = TestClient::connect(server);
+    let resp = client.upgrade("/").unwrap();
+    assert_eq!(resp.status_code.0, 101);
+    client
+}
+
+#[test]
+fn get() {
+    let server = hello_server();
+    let mut client = TestClient::connect(&server);
+    let resp = client.get("/hello").unwrap();
+    assert_eq!(resp.status_code.0, 200);
+    assert!(resp.headers.contains(&Header::ContentLength(5)));
+    assert_eq!(resp.body.unwrap().as_bytes().unwrap(), b"Hello");
+}
+
+#[test]
+fn pipelined_get() {
+    let server = hello_server();
+    let mut client = TestClient::connect(&server);
+    client.send(b"GET /hello HTTP/1.1\r\nHost: localhost\r\n\r\nGET /world HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    let first = client.read_response().unwrap();
+    let second = client.read_response().unwrap();
+    assert_eq!(first.body.unwrap().as_bytes().unwrap(), b"Hello");
+    assert_eq!(second.body.unwrap().as_bytes().unwrap(), b"World!");
+}
+
+#[test]
+fn head_has_no_body() {
+    let server = hello_server();
+    let mut client = TestClient::connect(&server);
+    let resp = client.request(b"HEAD /hello HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    assert_eq!(resp.status_code.0, 200);
+    assert!(resp.headers.contains(&Header::ContentLength(5)));
+    assert!(resp.body.is_none());
+    // Had the HEAD response a body, it would be read as the start of this response.
+    assert_eq!(client.get("/world").unwrap().body.unwrap().as_bytes().unwrap(), b"World!");
+}
+
+#[test]
+fn method_not_allowed() {
+    let server = hello_server();
+    let mut client = TestClient::connect(&server);
+    let resp = client.request(b"DELETE /hello HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    assert_eq!(resp.status_code.0, 405);
+    assert!(header(&resp.headers, "Allow").unwrap().contains("GET"));
+}
+
+#[test]
+fn malformed_request() {
+    let server = hello_server();
+    let mut client = TestClient::connect(&server);
+    let resp = client.request(b"GET /hello HTTP/1.1\r\nHost localhost\r\n\r\n").unwrap();
+    assert_eq!(resp.status_code.0, 400);
+    assert_eq!(header(&resp.headers, "Connection").as_deref(), Some("close"));
+}
+
+#[test]
+fn body_too_large() {
+    let server = hello_server();
+    server.configure(|config| config.set_max_body_len(4));
+    let mut client = TestClient::connect(&server);
+    let resp = client.request(b"POST /hello HTTP/1.1\r\nHost: localhost\r\nContent-Length: 10\r\n\r\n0123456789").unwrap();
+    assert_eq!(resp.status_code.0, 413);
+    assert_eq!(header(&resp.headers, "Connection").as_deref(), Some("close"));
+}
+
+#[test]
+fn request_timeout() {
+    let server = hello_server();
+    server.configure(|config| config.set_header_timeout(Duration::from_millis(100)));
+    let mut client = TestClient::connect(&server);
+    client.send(b"GET /hello HTTP/1.1\r\nHost: local").unwrap();
+    assert_eq!(client.read_response().unwrap().status_code.0, 408);
+}
+
+#[test]
+fn byte_ranges() {
+    let server = hello_server();
+    let mut client = TestClient::connect(&server);
+    let resp = client.request(b"GET /hello HTTP/1.1\r\nHost: localhost\r\nRange: bytes=1-3\r\n\r\n").unwrap();
+    assert_eq!(resp.status_code.0, 206);
+    assert_eq!(header(&resp.headers, "Content-Range").as_deref(), Some("bytes 1-3/5"));
+    assert_eq!(resp.body.unwrap().as_bytes().unwrap(), b"ell");
+    let resp = client.request(b"GET /hello HTTP/1.1\r\nHost: localhost\r\nRange: bytes=10-\r\n\r\n").unwrap();
+    assert_eq!(resp.status_code.0, 416);
+    assert_eq!(header(&resp.headers, "Content-Range").as_deref(), Some("bytes */5"));
+}
+
+#[test]
+fn not_modified() {
+    let server = hello_server();
+    let mut client = TestClient::connect(&server);
+    let etag = header(&client.get("/hello").unwrap().headers, "ETag").unwrap();
+    let request = format!("GET /hello HTTP/1.1\r\nHost: localhost\r\nIf-None-Match: {etag}\r\n\r\n");
+    let resp = client.request(request.as_bytes()).unwrap();
+    assert_eq!(resp.status_code.0, 304);
+    assert!(resp.body.is_none());
+}
+
+#[test]
+fn websocket_ping_and_close() {
+    let server = Server::new();
+    let mut client = upgraded_client(&server);
+    client.send_frame(true, Opcode::Ping, b"ping").unwrap();
+    let pong = client.read_frame().unwrap();
+    assert_eq!((pong.opcode, pong.payload.as_slice()), (Opcode::Pong, &b"ping"[..]));
+    client.send_frame(true, Opcode::Close, &1000u16.to_be_bytes()).unwrap();
+    let close = client.read_frame().unwrap();
+    assert_eq!((close.opcode, close.payload.as_slice()), (Opcode::Close, &1000u16.to_be_bytes()[..]));
+    assert_eq!(client.read_message().unwrap(), None);
+}
+
+#[test]
+fn websocket_fragmented_message() {
+    let server = Server::new();
+    let (sender, receiver) = mpsc::channel();
+    let sender = Mutex::new(sender);
+    server.configure(|config| config.on_websocket_message(move |addr, message| {
+        let _ = sender.lock().unwrap().send((addr, message));
+    }));
+    let mut client = upgraded_client(&server);
+    client.send_frame(false, Opcode::Text, b"Hello, ").unwrap();
+    // Control frames may come between the fragments of a message.
+    client.send_frame(true, Opcode::Ping, b"").unwrap();
+    client.send_frame(false, Opcode::Continuation, b"fragmented ").unwrap();
+    client.send_frame(true, Opcode::Continuation, b"world").unwrap();
+    let (addr, message) = receiver.recv_timeout(Duration::from_secs(10)).unwrap();
+    assert_eq!(addr, client.addr());
+    assert_eq!(message, Message::Text("Hello, fragmented world".into()));
+}
+
+#[test]
+fn websocket_large_broadcast() {
+    let server = Server::new();
+    let mut client = upgraded_client(&server);
+    // The pong comes from the WebSocket hub, so the client is known to be there before broadcasting.
+    client.send_frame(true, Opcode::Ping, b"").unwrap();
+    assert_eq!(client.read_frame().unwrap().opcode, Opcode::Pong);
+    let text = "a".repeat(70_000);
+    server.broadcast(text.as_bytes());
+    assert_eq!(client.read_message().unwrap(), Some(Message::Text(text)));
+}